@@ -0,0 +1,109 @@
+//! Block truncation coding in the style of BC1/DXT1. Every 4x4 block stores
+//! two RGB565 endpoints followed by 1 or 2 bits per pixel. With 2 bits, blocks
+//! are laid out exactly like BC1 blocks.
+
+use crate::{color::RgbU8, Distance};
+use image::Rgb;
+
+/// Width and height of a block in pixels
+pub const BLOCK_SIZE: u32 = 4;
+const BLOCK_PIXELS: usize = (BLOCK_SIZE * BLOCK_SIZE) as usize;
+
+fn to_565(color: RgbU8) -> u16 {
+    let [r, g, b] = color.0.map(u16::from);
+    let r = (r * 31 + 127) / 255;
+    let g = (g * 63 + 127) / 255;
+    let b = (b * 31 + 127) / 255;
+    (r << 11) | (g << 5) | b
+}
+
+fn from_565(color: u16) -> RgbU8 {
+    let r = (color >> 11) as u8 & 0x1f;
+    let g = (color >> 5) as u8 & 0x3f;
+    let b = color as u8 & 0x1f;
+    RgbU8([
+        (r << 3) | (r >> 2),
+        (g << 2) | (g >> 4),
+        (b << 3) | (b >> 2),
+    ])
+}
+
+/// Returns the colors a block's indices refer to. With 2 bits and `c0 > c1`
+/// the endpoints are interpolated at thirds, otherwise at the midpoint
+/// followed by black, as in BC1
+pub fn palette(c0: u16, c1: u16, bits: u8) -> Vec<RgbU8> {
+    let (a, b) = (from_565(c0), from_565(c1));
+    if bits == 1 {
+        return vec![a, b];
+    }
+
+    let mix = |wa: u16, wb: u16| {
+        RgbU8(std::array::from_fn(|c| {
+            ((wa * u16::from(a.0[c]) + wb * u16::from(b.0[c])) / (wa + wb)) as u8
+        }))
+    };
+    if c0 > c1 {
+        vec![a, b, mix(2, 1), mix(1, 2)]
+    } else {
+        vec![a, b, mix(1, 1), RgbU8([0; 3])]
+    }
+}
+
+/// Encodes a block, choosing the pair of endpoints with the smallest total
+/// squared distance in the color space `T`. Endpoints are picked among the
+/// block's own colors
+pub fn encode<T>(block: &[RgbU8; BLOCK_PIXELS], bits: u8) -> Vec<u8>
+where
+    T: Distance<Output = f32> + From<Rgb<u8>>,
+{
+    assert!((1..=2).contains(&bits), "block index bits must be 1 or 2");
+
+    let pixels = block.map(|color| T::from(Rgb(color.0)));
+    let mut endpoints: Vec<u16> = block.iter().map(|&color| to_565(color)).collect();
+    endpoints.sort_unstable();
+    endpoints.dedup();
+
+    // Pairs are ordered so that c0 > c1, which selects the four color mode
+    let mut pairs: Vec<(u16, u16)> = endpoints
+        .iter()
+        .enumerate()
+        .flat_map(|(i, &c1)| endpoints[i + 1..].iter().map(move |&c0| (c0, c1)))
+        .collect();
+    if pairs.is_empty() {
+        pairs.push((endpoints[0], endpoints[0]));
+    }
+
+    let mut best = (f32::INFINITY, (0, 0), [0u8; BLOCK_PIXELS]);
+    for (c0, c1) in pairs {
+        let palette: Vec<T> = palette(c0, c1, bits)
+            .into_iter()
+            .map(|color| Rgb(color.0).into())
+            .collect();
+
+        let mut error = 0.0;
+        let mut indices = [0u8; BLOCK_PIXELS];
+        for (pixel, index) in pixels.iter().zip(&mut indices) {
+            let (i, distance) = palette
+                .iter()
+                .map(|color| pixel.distance2(color))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .unwrap();
+            *index = i as u8;
+            error += distance;
+        }
+        if error < best.0 {
+            best = (error, (c0, c1), indices);
+        }
+    }
+
+    let (_, (c0, c1), indices) = best;
+    let mut bytes = Vec::with_capacity(8);
+    bytes.extend_from_slice(&c0.to_le_bytes());
+    bytes.extend_from_slice(&c1.to_le_bytes());
+    let packed = indices.iter().enumerate().fold(0u32, |acc, (i, &index)| {
+        acc | u32::from(index) << (i * usize::from(bits))
+    });
+    bytes.extend_from_slice(&packed.to_le_bytes()[..2 * usize::from(bits)]);
+    bytes
+}
@@ -4,7 +4,7 @@ use image::Rgb;
 use std::{
     hash::{Hash, Hasher},
     iter::Sum,
-    ops::{AddAssign, Div, Index},
+    ops::{AddAssign, Div, Index, Mul},
 };
 
 // https://en.wikipedia.org/wiki/CIELAB_color_space
@@ -35,6 +35,14 @@ impl Index<usize> for CieLab {
     }
 }
 
+impl Mul<f32> for CieLab {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        CieLab(self.0.map(|x| x * rhs))
+    }
+}
+
 impl Sum for CieLab {
     fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
         let mut sum = [0f32; 3];
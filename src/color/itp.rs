@@ -7,7 +7,7 @@ use image::Rgb;
 use std::{
     hash::{Hash, Hasher},
     iter::Sum,
-    ops::{AddAssign, Div, Index},
+    ops::{AddAssign, Div, Index, Mul},
 };
 
 // https://www.itu.int/dms_pubrec/itu-r/rec/bt/R-REC-BT.2124-0-201901-I!!PDF-E.pdf
@@ -38,6 +38,14 @@ impl Index<usize> for Itp {
     }
 }
 
+impl Mul<f32> for Itp {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Itp(self.0.map(|x| x * rhs))
+    }
+}
+
 impl Sum for Itp {
     fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
         let mut sum = [0f32; 3];
@@ -1,119 +1,1069 @@
+mod quality;
+mod rate;
+mod tiles;
+
+pub use quality::{DeltaEStatistic, QualityTarget};
+pub use rate::Target;
+
 use crate::{
+    block::{self, BLOCK_SIZE},
     color::{CieLab, Itp, RgbU8},
-    kmeans, Distance, Image, PaletteMethod,
+    export::Paletted,
+    index_bits, kmeans, median_cut,
+    metrics::DeltaE,
+    nearest::{LookupCache, Nearest, NearestSearch},
+    neuquant,
+    octree::Octree,
+    palette, par, wu, ColorSpace, Distance, Image, MedianCutSplit, Mode, PaletteMethod,
+    TilePalette, MAGIC, VERSION,
 };
-use std::collections::HashMap;
+use image::Rgb;
+use libflate::deflate::Encoder;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::ops::Mul;
 
-const LOG2_PALETTE_SIZE: u8 = 4;
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub palette_method: PaletteMethod,
+    /// Maximum number of colors in the palette, from 1 to 256
+    pub palette_size: u16,
+    /// Bits per channel of the nearest-color lookup cache. Colors that fall
+    /// into the same cell share a palette index, which is faster but less
+    /// accurate. `None` searches the palette for every pixel
+    pub lookup_cache: Option<u8>,
+    /// How median cut picks the next box to split
+    pub median_cut_split: MedianCutSplit,
+    /// Color space median cut splits the colors in
+    pub median_cut_space: ColorSpace,
+    /// NeuQuant learns from every `neuquant_sample_factor`th pixel, from 1
+    /// (best quality) to 30 (fastest)
+    pub neuquant_sample_factor: u8,
+    /// Bits per channel used to group colors for the frequency palette, from
+    /// 1 to 8
+    pub freq_bin_bits: u8,
+    /// Minimum redmean distance between frequency palette colors. It is
+    /// relaxed if there would be fewer colors than requested
+    pub freq_min_spacing: f32,
+    /// Move palette colors to the mean of their pixels after mapping, until
+    /// the error stops improving
+    pub refine: bool,
+    /// Whether the image has one palette, one per tile, or is stored as
+    /// blocks
+    pub layout: Layout,
+    /// Store images with at most 256 unique colors exactly, using as many
+    /// palette colors as needed and ignoring the other settings. Only applies
+    /// to the global layout
+    pub lossless: bool,
+    /// Strength of Floyd-Steinberg dithering, from 0 (off) to 1
+    pub dither: f32,
+    /// Size or quality to reach by picking some of the settings
+    /// automatically
+    pub goal: Option<Goal>,
+    /// Colors used as they are by [`PaletteMethod::Fixed`], at most 256.
+    /// `palette_size`, `refine` and `lossless` don't apply to it
+    pub fixed_palette: Vec<Rgb<u8>>,
+    /// With [`PaletteMethod::Fixed`], store the hash of `fixed_palette`
+    /// instead of the palette, for images sharing one. Decoding then needs
+    /// the palette, see [`decompress_with_palette`]. Tiles still store
+    /// their palettes
+    ///
+    /// [`decompress_with_palette`]: crate::decompress::decompress_with_palette
+    pub reference_palette: bool,
+}
 
-pub fn compress(img: &Image, palette_method: PaletteMethod) -> Vec<u8> {
-    let width = usize::try_from(img.width()).unwrap();
-    let height = usize::try_from(img.height()).unwrap();
-    let mut bytes = Vec::with_capacity(12 + 48 + (width * height).div_ceil(2));
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            palette_method: PaletteMethod::Freq,
+            palette_size: 16,
+            lookup_cache: None,
+            median_cut_split: MedianCutSplit::Variance,
+            median_cut_space: ColorSpace::CieLab,
+            neuquant_sample_factor: 10,
+            freq_bin_bits: 4,
+            freq_min_spacing: 32.0,
+            refine: false,
+            layout: Layout::Global,
+            lossless: true,
+            dither: 0.0,
+            goal: None,
+            fixed_palette: Vec::new(),
+            reference_palette: false,
+        }
+    }
+}
 
-    // Header
-    let width = img.width().to_le_bytes();
-    let height = img.height().to_le_bytes();
-    bytes.extend_from_slice(&width);
-    bytes.extend_from_slice(&height);
+impl Options {
+    /// Checks that every setting is in range
+    pub fn check(&self) -> Result<(), OptionsError> {
+        if !(1..=256).contains(&self.palette_size) {
+            return Err(OptionsError::PaletteSize(self.palette_size));
+        }
+        if matches!(self.palette_method, PaletteMethod::Fixed)
+            && !(1..=256).contains(&self.fixed_palette.len())
+        {
+            return Err(OptionsError::FixedPaletteSize(self.fixed_palette.len()));
+        }
+        if let Some(bits) = self.lookup_cache.filter(|bits| !(1..=8).contains(bits)) {
+            return Err(OptionsError::LookupCacheBits(bits));
+        }
+        if !(1..=30).contains(&self.neuquant_sample_factor) {
+            return Err(OptionsError::SampleFactor(self.neuquant_sample_factor));
+        }
+        if !(1..=8).contains(&self.freq_bin_bits) {
+            return Err(OptionsError::FreqBinBits(self.freq_bin_bits));
+        }
+        if !(self.freq_min_spacing.is_finite() && self.freq_min_spacing >= 0.0) {
+            return Err(OptionsError::FreqMinSpacing(self.freq_min_spacing));
+        }
+        if !(0.0..=1.0).contains(&self.dither) {
+            return Err(OptionsError::Dither(self.dither));
+        }
+        match self.layout {
+            Layout::Tiles(0, _) => Err(OptionsError::TileSize),
+            Layout::Blocks(bits) if !(1..=2).contains(&bits) => Err(OptionsError::BlockBits(bits)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why [`Options::check`] rejected the options
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionsError {
+    PaletteSize(u16),
+    FixedPaletteSize(usize),
+    LookupCacheBits(u8),
+    SampleFactor(u8),
+    FreqBinBits(u8),
+    FreqMinSpacing(f32),
+    Dither(f32),
+    TileSize,
+    BlockBits(u8),
+}
 
-    match palette_method {
-        PaletteMethod::Freq => compress_freq(&mut bytes, img),
-        PaletteMethod::KMeans => compress_k_means(&mut bytes, img),
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionsError::PaletteSize(size) => {
+                write!(f, "palette size must be in 1..=256, got {}", size)
+            }
+            OptionsError::FixedPaletteSize(size) => {
+                write!(f, "fixed palette must have 1 to 256 colors, got {}", size)
+            }
+            OptionsError::LookupCacheBits(bits) => {
+                write!(f, "cache bits must be in 1..=8, got {}", bits)
+            }
+            OptionsError::SampleFactor(factor) => {
+                write!(f, "sample factor must be in 1..=30, got {}", factor)
+            }
+            OptionsError::FreqBinBits(bits) => {
+                write!(f, "bin bits must be in 1..=8, got {}", bits)
+            }
+            OptionsError::FreqMinSpacing(spacing) => write!(
+                f,
+                "minimum spacing must be a finite number of at least 0, got {}",
+                spacing
+            ),
+            OptionsError::Dither(strength) => {
+                write!(f, "dithering strength must be in 0..=1, got {}", strength)
+            }
+            OptionsError::TileSize => write!(f, "tile size must not be 0"),
+            OptionsError::BlockBits(bits) => {
+                write!(f, "block index bits must be 1 or 2, got {}", bits)
+            }
+        }
     }
+}
 
-    bytes
+impl std::error::Error for OptionsError {}
+
+/// How the pixels of an image are stored
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layout {
+    /// One palette for the whole image
+    Global,
+    /// Tiles of this many pixels square, each with its own palette of up to
+    /// `palette_size` colors, stored as given
+    Tiles(u16, TilePalette),
+    /// 4x4 blocks of two endpoint colors and 1 or 2 bits per pixel instead
+    /// of a palette. With 2 bits the blocks are BC1 blocks
+    Blocks(u8),
 }
 
-fn compress_freq(bytes: &mut Vec<u8>, img: &Image) {
-    let palette_size = 2u16.pow(LOG2_PALETTE_SIZE.into());
-    let palette = get_palette_freq(img, palette_size);
+/// A size or quality for [`compress`] to reach
+#[derive(Debug, Copy, Clone)]
+pub enum Goal {
+    /// Size budget. The palette size, dithering and tiling are chosen to give
+    /// the lowest error that fits, and `layout` is ignored
+    Size(Target),
+    /// Largest allowed color error. The palette is grown and then dithering
+    /// enabled until it is met, and `palette_size`, `dither` and blocks are
+    /// ignored
+    Quality(QualityTarget),
+}
 
-    // Header
-    bytes.extend_from_slice(&u32::try_from(palette.len()).unwrap().to_le_bytes());
-    for &color in &palette {
-        bytes.extend_from_slice(&color.0);
+/// Upper bound on the number of palette refinement passes
+const MAX_REFINE_ITERATIONS: usize = 32;
+
+/// Colors that can be averaged channel by channel in their own color space
+trait Channels: Copy {
+    fn channels(&self) -> [f32; 3];
+    fn from_channels(channels: [f32; 3]) -> Self;
+}
+
+impl Channels for RgbU8 {
+    fn channels(&self) -> [f32; 3] {
+        self.0.map(f32::from)
     }
 
-    // Data
-    for (i, &pixel) in img.pixels().enumerate() {
-        let pixel: RgbU8 = pixel.into();
-        // TODO: use dithering
-        let index = pixel.nearest(&palette).unwrap();
-        let index = u8::try_from(index).unwrap();
-        // Each index is 4 bits, so we can fit 2 indices in a byte
-        if i % 2 == 0 {
-            bytes.push(index);
-        } else {
-            *bytes.last_mut().unwrap() |= index << 4;
+    fn from_channels(channels: [f32; 3]) -> Self {
+        RgbU8(channels.map(|c| c.round().clamp(0.0, 255.0) as u8))
+    }
+}
+
+impl Channels for CieLab {
+    fn channels(&self) -> [f32; 3] {
+        self.0
+    }
+
+    fn from_channels(channels: [f32; 3]) -> Self {
+        CieLab(channels)
+    }
+}
+
+impl Channels for Itp {
+    fn channels(&self) -> [f32; 3] {
+        self.0
+    }
+
+    fn from_channels(channels: [f32; 3]) -> Self {
+        Itp(channels)
+    }
+}
+
+/// A compressed file and the settings that produced it
+#[derive(Debug, Clone)]
+pub struct Compressed {
+    pub bytes: Vec<u8>,
+    pub palette_size: u16,
+    pub dither: f32,
+    pub layout: Layout,
+    /// The image had few enough colors to be stored exactly
+    pub lossless: bool,
+    /// Error of the decoded image, measured when compressing for a quality
+    /// target
+    pub delta_e: Option<DeltaE>,
+}
+
+/// Compresses the image into an imgcpr file
+pub fn compress(img: &Image, options: &Options) -> Result<Compressed, OptionsError> {
+    options.check()?;
+    Ok(compress_checked(img, options))
+}
+
+/// [`compress`] with options that passed [`Options::check`]
+fn compress_checked(img: &Image, options: &Options) -> Compressed {
+    match options.goal {
+        None => {
+            let (bytes, lossless) = encode(img, options);
+            Compressed {
+                bytes: deflate(&bytes),
+                palette_size: options.palette_size,
+                dither: options.dither,
+                layout: options.layout,
+                lossless,
+                delta_e: None,
+            }
+        }
+        Some(Goal::Size(target)) => rate::compress(img, options, target),
+        Some(Goal::Quality(quality)) => quality::compress(img, options, quality),
+    }
+}
+
+/// Quantizes the image to a single palette without writing an imgcpr file,
+/// for exporting with [`Paletted::write_png`] or [`Paletted::write_gif`].
+/// Tiles, blocks and size or quality targets don't apply
+pub fn palettize(img: &Image, options: &Options) -> Result<Paletted, OptionsError> {
+    options.check()?;
+
+    let rgb: Vec<RgbU8> = img.pixels().map(|&p| p.into()).collect();
+    let exact = exact_palette(&rgb, options);
+    let quantized = match exact {
+        Some(palette) => map_exact(&rgb, palette),
+        None => {
+            let width = usize::try_from(img.width()).unwrap();
+            quantize(&rgb, width, options, &PaletteCoding::Rgb)
+        }
+    };
+
+    Ok(Paletted {
+        width: img.width(),
+        height: img.height(),
+        palette: quantized.palette.iter().map(|c| Rgb(c.0)).collect(),
+        indices: quantized.indices.iter().map(|&i| i as u8).collect(),
+    })
+}
+
+/// How many pixels of each color a set of images has, counted one image at a
+/// time for [`shared_palette`]
+#[derive(Debug, Clone, Default)]
+pub struct ColorCounts(HashMap<RgbU8, u64>);
+
+impl ColorCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the pixels of another image
+    pub fn add(&mut self, img: &Image) {
+        let rgb: Vec<RgbU8> = img.pixels().map(|&p| p.into()).collect();
+        for (color, count) in histogram(&rgb, |&pixel| pixel) {
+            *self.0.entry(color).or_default() += u64::from(count);
+        }
+    }
+}
+
+/// Computes one palette of up to `palette_size` colors for a set of images,
+/// to compress each of them with [`PaletteMethod::Fixed`]. The colors of all
+/// images are clustered together in CIELAB, weighted by their number of
+/// pixels, starting from a median cut. If `lossless` is set and the images
+/// have at most 256 colors together, those colors are used exactly.
+///
+/// Returns the colors with the number of pixels closest to each, most used
+/// first. Colors no pixel is closest to are left out
+pub fn shared_palette(
+    counts: &ColorCounts,
+    options: &Options,
+) -> Result<Vec<(Rgb<u8>, u64)>, OptionsError> {
+    options.check()?;
+
+    let mut colors: Vec<(RgbU8, u64)> = counts
+        .0
+        .iter()
+        .map(|(&color, &count)| (color, count))
+        .collect();
+    colors.sort_unstable_by_key(|&(color, _)| color.0);
+
+    let palette: Vec<RgbU8> = if options.lossless && colors.len() <= 256 {
+        colors.iter().map(|&(color, _)| color).collect()
+    } else {
+        let weighted: Vec<(CieLab, u32)> = colors
+            .iter()
+            .map(|&(color, count)| {
+                let weight = u32::try_from(count).unwrap_or(u32::MAX);
+                (Rgb(color.0).into(), weight)
+            })
+            .collect();
+        let centroids = median_cut::fit(
+            &weighted,
+            options.palette_size.into(),
+            options.median_cut_split,
+        );
+        let mut palette: Vec<RgbU8> = kmeans::fit_weighted(&weighted, centroids, 0.00005, 250)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        // Centroids may round to the same color
+        let mut seen = HashSet::new();
+        palette.retain(|color| seen.insert(*color));
+        palette
+    };
+
+    let mut populations = vec![0u64; palette.len()];
+    let search = RgbU8::search(&palette);
+    for (color, count) in colors {
+        if let Some(index) = search.nearest(&color) {
+            populations[index] += count;
         }
     }
+    let mut palette: Vec<(Rgb<u8>, u64)> = palette
+        .into_iter()
+        .zip(populations)
+        .filter(|&(_, count)| count > 0)
+        .map(|(color, count)| (Rgb(color.0), count))
+        .collect();
+    palette.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    Ok(palette)
+}
+
+// TODO: try png- or qoi-like compression on index data
+// Deflate performs best, at 122.1 KB for bright-colors
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(Vec::new());
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().into_result().unwrap()
 }
 
-fn compress_k_means(bytes: &mut Vec<u8>, img: &Image) {
-    let palette_size = 2u16.pow(LOG2_PALETTE_SIZE.into());
-    let pixels: Vec<CieLab> = img.pixels().map(|&p| p.into()).collect();
-    let palette = get_palette_k_means(&pixels, palette_size);
+/// Returns the file contents before entropy coding, and whether the image was
+/// stored exactly
+fn encode(img: &Image, options: &Options) -> (Vec<u8>, bool) {
+    let rgb: Vec<RgbU8> = img.pixels().map(|&p| p.into()).collect();
+    // Tiles and blocks are kept when asked for, even if one palette would be
+    // exact
+    let exact = match options.layout {
+        Layout::Global => exact_palette(&rgb, options),
+        Layout::Tiles(..) | Layout::Blocks(_) => None,
+    };
+
+    let referenced =
+        options.reference_palette && matches!(options.palette_method, PaletteMethod::Fixed);
 
     // Header
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(VERSION);
+    let mode = match options.layout {
+        Layout::Global if referenced && exact.is_none() => Mode::Referenced,
+        Layout::Global => Mode::Global,
+        Layout::Tiles(..) => Mode::Tiled,
+        Layout::Blocks(_) => Mode::Block,
+    };
+    bytes.push(mode as u8);
+    bytes.extend_from_slice(&img.width().to_le_bytes());
+    bytes.extend_from_slice(&img.height().to_le_bytes());
+
+    let lossless = exact.is_some();
+    match (exact, options.layout) {
+        (Some(palette), _) => write_global(&mut bytes, &map_exact(&rgb, palette)),
+        (None, Layout::Tiles(tile_size, tile_palette)) => {
+            tiles::write(&mut bytes, img, &rgb, tile_size, tile_palette, options)
+        }
+        (None, Layout::Blocks(bits)) => write_blocks(&mut bytes, img, &rgb, bits),
+        (None, Layout::Global) => {
+            let width = usize::try_from(img.width()).unwrap();
+            let quantized = quantize(&rgb, width, options, &PaletteCoding::Rgb);
+            if referenced {
+                write_referenced(&mut bytes, &quantized);
+            } else {
+                write_global(&mut bytes, &quantized);
+            }
+        }
+    }
+
+    (bytes, lossless)
+}
+
+/// Writes a single palette for the whole image and the indices of every pixel
+fn write_global(bytes: &mut Vec<u8>, quantized: &Quantized) {
+    let palette_size = quantized.palette.len();
+    bytes.extend_from_slice(&u32::try_from(palette_size).unwrap().to_le_bytes());
+    bytes.extend_from_slice(&quantized.coded);
+    write_indices(bytes, &quantized.indices, index_bits(palette_size));
+}
+
+/// Writes the hash of the palette in place of the palette, and the indices of
+/// every pixel
+fn write_referenced(bytes: &mut Vec<u8>, quantized: &Quantized) {
+    let palette: Vec<Rgb<u8>> = quantized.palette.iter().map(|c| Rgb(c.0)).collect();
+    bytes.extend_from_slice(&palette::hash(&palette).to_le_bytes());
     bytes.extend_from_slice(&u32::try_from(palette.len()).unwrap().to_le_bytes());
-    for &color in &palette {
-        let color: RgbU8 = color.into();
-        bytes.extend_from_slice(&color.0);
+    write_indices(bytes, &quantized.indices, index_bits(palette.len()));
+}
+
+/// Returns the palette storing the image exactly, if `lossless` is set, the
+/// palette isn't fixed and the image has at most 256 colors
+fn exact_palette(rgb: &[RgbU8], options: &Options) -> Option<Vec<RgbU8>> {
+    if options.lossless && !matches!(options.palette_method, PaletteMethod::Fixed) {
+        unique_colors(rgb)
+    } else {
+        None
+    }
+}
+
+/// Returns every color in the image, sorted, if there are at most 256
+fn unique_colors(rgb: &[RgbU8]) -> Option<Vec<RgbU8>> {
+    let mut colors = HashSet::new();
+    for &pixel in rgb {
+        if colors.insert(pixel) && colors.len() > 256 {
+            return None;
+        }
+    }
+    let mut colors: Vec<_> = colors.into_iter().collect();
+    colors.sort_unstable_by_key(|color| color.0);
+    Some(colors)
+}
+
+/// Maps every pixel to its own color in a palette holding every color of the
+/// image
+fn map_exact(rgb: &[RgbU8], palette: Vec<RgbU8>) -> Quantized {
+    let lookup: HashMap<RgbU8, usize> = palette.iter().enumerate().map(|(i, &c)| (c, i)).collect();
+    Quantized {
+        coded: palette.iter().flat_map(|c| c.0).collect(),
+        indices: par::map(rgb, |pixel| lookup[pixel]),
+        palette,
+    }
+}
+
+/// Writes every 4x4 block in row-major order. Blocks on the right and bottom
+/// edges are padded by repeating the last column and row
+fn write_blocks(bytes: &mut Vec<u8>, img: &Image, rgb: &[RgbU8], bits: u8) {
+    bytes.push(bits);
+
+    let width = usize::try_from(img.width()).unwrap();
+    let height = usize::try_from(img.height()).unwrap();
+    let blocks: Vec<_> = crate::tiles(img.width(), img.height(), BLOCK_SIZE).collect();
+    let blocks = par::map(&blocks, |&(x, y, _, _)| {
+        let [x, y] = [x, y].map(|v| usize::try_from(v).unwrap());
+        let size = BLOCK_SIZE as usize;
+        let block = std::array::from_fn(|i| {
+            let px = (x + i % size).min(width - 1);
+            let py = (y + i / size).min(height - 1);
+            rgb[py * width + px]
+        });
+        block::encode::<CieLab>(&block, bits)
+    });
+    for block in blocks {
+        bytes.extend_from_slice(&block);
+    }
+}
+
+/// A palette and the index of the nearest palette color for every pixel
+struct Quantized {
+    palette: Vec<RgbU8>,
+    /// The palette as written to the file
+    coded: Vec<u8>,
+    indices: Vec<usize>,
+}
+
+/// Builds a palette for `rgb`, an image `width` pixels wide, with the
+/// configured method and maps every pixel to it
+fn quantize(rgb: &[RgbU8], width: usize, options: &Options, coding: &PaletteCoding) -> Quantized {
+    match options.palette_method {
+        PaletteMethod::Freq => {
+            let palette = get_palette_freq(
+                rgb,
+                options.palette_size,
+                options.freq_bin_bits,
+                options.freq_min_spacing,
+            );
+            map_palette(rgb, rgb, width, palette, options, coding)
+        }
+        PaletteMethod::KMeans => {
+            let pixels: Vec<CieLab> = par::map(rgb, |&p| Rgb(p.0).into());
+            let palette = get_palette_k_means(rgb, &pixels, options.palette_size);
+            map_palette(rgb, &pixels, width, palette, options, coding)
+        }
+        PaletteMethod::MedianCut => match options.median_cut_space {
+            ColorSpace::CieLab => {
+                let pixels: Vec<CieLab> = par::map(rgb, |&p| Rgb(p.0).into());
+                let palette =
+                    get_palette_median_cut(rgb, options.palette_size, options.median_cut_split);
+                map_palette(rgb, &pixels, width, palette, options, coding)
+            }
+            ColorSpace::Itp => {
+                let pixels: Vec<Itp> = par::map(rgb, |&p| Rgb(p.0).into());
+                let palette =
+                    get_palette_median_cut(rgb, options.palette_size, options.median_cut_split);
+                map_palette(rgb, &pixels, width, palette, options, coding)
+            }
+        },
+        PaletteMethod::Octree => {
+            let palette = get_palette_octree(rgb, options.palette_size);
+            map_palette(rgb, rgb, width, palette, options, coding)
+        }
+        PaletteMethod::Wu => {
+            let palette = wu::palette(rgb, options.palette_size.into());
+            map_palette(rgb, rgb, width, palette, options, coding)
+        }
+        PaletteMethod::NeuQuant => {
+            let palette = neuquant::palette(
+                rgb,
+                options.palette_size.into(),
+                options.neuquant_sample_factor,
+            );
+            map_palette(rgb, rgb, width, palette, options, coding)
+        }
+        PaletteMethod::Fixed => {
+            let palette = options.fixed_palette.iter().map(|&c| c.into()).collect();
+            // Refining would move the colors away from the given ones
+            let options = Options {
+                refine: false,
+                ..options.clone()
+            };
+            map_palette(rgb, rgb, width, palette, &options, coding)
+        }
+    }
+}
+
+/// Maps every pixel to the nearest palette color. `rgb` and `pixels` are the
+/// same pixels, in RGB and in the palette's color space
+fn map_palette<T>(
+    rgb: &[RgbU8],
+    pixels: &[T],
+    width: usize,
+    palette: Vec<T>,
+    options: &Options,
+    coding: &PaletteCoding,
+) -> Quantized
+where
+    T: Channels + Into<RgbU8> + Nearest + Distance<Output = f32> + From<Rgb<u8>> + Sync,
+{
+    let indices = map_indices(rgb, pixels, width, &palette, options);
+    let (palette, indices) = if options.refine {
+        refine(rgb, pixels, width, palette, indices, options)
+    } else {
+        (palette, indices)
+    };
+
+    let colors: Vec<RgbU8> = palette.into_iter().map(Into::into).collect();
+    let (palette, coded) = coding.code(&colors);
+    // Coding may move colors, in which case the pixels are mapped again
+    let indices = if palette == colors {
+        indices
+    } else {
+        let palette: Vec<T> = palette.iter().map(|c| Rgb(c.0).into()).collect();
+        map_indices(rgb, pixels, width, &palette, options)
+    };
+
+    Quantized {
+        palette,
+        coded,
+        indices,
     }
+}
+
+/// How palette colors are written
+enum PaletteCoding<'a> {
+    /// RGB triplets
+    Rgb,
+    /// Indices into a global palette
+    Shared(&'a GlobalPalette),
+}
 
-    // Data
-    for (i, pixel) in pixels.iter().enumerate() {
-        // TODO: use dithering
-        let index = pixel.nearest(&palette).unwrap();
+struct GlobalPalette {
+    colors: Vec<RgbU8>,
+    search: <RgbU8 as Nearest>::Search,
+}
+
+impl GlobalPalette {
+    fn new(colors: Vec<RgbU8>) -> Self {
+        GlobalPalette {
+            search: RgbU8::search(&colors),
+            colors,
+        }
+    }
+}
+
+impl PaletteCoding<'_> {
+    /// Returns the colors as they will be decoded, and their bytes
+    fn code(&self, colors: &[RgbU8]) -> (Vec<RgbU8>, Vec<u8>) {
+        match self {
+            PaletteCoding::Rgb => (colors.to_vec(), colors.iter().flat_map(|c| c.0).collect()),
+            PaletteCoding::Shared(global) => {
+                // Colors that share their nearest global color are merged
+                let mut indices: Vec<usize> = Vec::with_capacity(colors.len());
+                for color in colors {
+                    let index = global.search.nearest(color).unwrap();
+                    if !indices.contains(&index) {
+                        indices.push(index);
+                    }
+                }
+                let palette = indices.iter().map(|&i| global.colors[i]).collect();
+                let coded = indices.iter().map(|&i| u8::try_from(i).unwrap()).collect();
+                (palette, coded)
+            }
+        }
+    }
+}
+
+/// Moves every palette color to the mean of the pixels mapped to it and maps
+/// the pixels again, for as long as the total squared error keeps going down
+fn refine<T>(
+    rgb: &[RgbU8],
+    pixels: &[T],
+    width: usize,
+    mut palette: Vec<T>,
+    mut indices: Vec<usize>,
+    options: &Options,
+) -> (Vec<T>, Vec<usize>)
+where
+    T: Channels + Into<RgbU8> + Nearest + Distance<Output = f32> + From<Rgb<u8>> + Sync,
+{
+    let error = |palette: &[T], indices: &[usize]| -> f64 {
+        pixels
+            .iter()
+            .zip(indices)
+            .map(|(pixel, &i)| f64::from(pixel.distance2(&palette[i])))
+            .sum()
+    };
+
+    let mut best = error(&palette, &indices);
+    for _ in 0..MAX_REFINE_ITERATIONS {
+        let mut sums = vec![(0u32, [0f64; 3]); palette.len()];
+        for (pixel, &i) in pixels.iter().zip(&indices) {
+            sums[i].0 += 1;
+            for (sum, c) in sums[i].1.iter_mut().zip(pixel.channels()) {
+                *sum += f64::from(c);
+            }
+        }
+        // Colors without any pixels stay where they are
+        let candidate: Vec<T> = sums
+            .iter()
+            .zip(&palette)
+            .map(|(&(count, sum), &color)| match count {
+                0 => color,
+                _ => T::from_channels(sum.map(|s| (s / f64::from(count)) as f32)),
+            })
+            .collect();
+
+        let candidate_indices = map_indices(rgb, pixels, width, &candidate, options);
+        let candidate_error = error(&candidate, &candidate_indices);
+        if candidate_error >= best {
+            break;
+        }
+        (palette, indices, best) = (candidate, candidate_indices, candidate_error);
+    }
+
+    (palette, indices)
+}
+
+/// Finds the nearest palette color for every pixel, diffusing the error if
+/// dithering is enabled. `rgb` and `pixels` are the same pixels, in RGB and in
+/// the palette's color space
+fn map_indices<T>(
+    rgb: &[RgbU8],
+    pixels: &[T],
+    width: usize,
+    palette: &[T],
+    options: &Options,
+) -> Vec<usize>
+where
+    T: Copy + Into<RgbU8> + Nearest + From<Rgb<u8>> + Sync,
+{
+    let search = T::search(palette);
+    let cache = options
+        .lookup_cache
+        .map(|bits| LookupCache::new(bits, &search));
+
+    if options.dither > 0.0 {
+        let colors: Vec<RgbU8> = palette.iter().map(|&color| color.into()).collect();
+        return dither(rgb, width, &colors, options.dither, |color| match &cache {
+            Some(cache) => cache.nearest(color),
+            None => search.nearest(&Rgb(color.0).into()).unwrap(),
+        });
+    }
+    match cache {
+        Some(cache) => par::map(rgb, |pixel| cache.nearest(pixel)),
+        None => par::map(pixels, |pixel| search.nearest(pixel).unwrap()),
+    }
+}
+
+/// Floyd-Steinberg error diffusion. `strength` scales the diffused error, from
+/// 0 (none) to 1 (full)
+fn dither<F>(
+    rgb: &[RgbU8],
+    width: usize,
+    palette: &[RgbU8],
+    strength: f32,
+    nearest: F,
+) -> Vec<usize>
+where
+    F: Fn(&RgbU8) -> usize,
+{
+    // Errors carried to the current and next row, with a column of padding
+    // on either side
+    let mut current = vec![[0f32; 3]; width + 2];
+    let mut next = vec![[0f32; 3]; width + 2];
+    let mut indices = Vec::with_capacity(rgb.len());
+    for row in rgb.chunks(width) {
+        for (x, pixel) in row.iter().enumerate() {
+            // Clamped so that error can't build up past what the palette
+            // can show
+            let wanted: [f32; 3] = std::array::from_fn(|c| {
+                (f32::from(pixel.0[c]) + current[x + 1][c]).clamp(0.0, 255.0)
+            });
+            let index = nearest(&RgbU8(wanted.map(|c| c.round() as u8)));
+            indices.push(index);
+
+            for c in 0..3 {
+                let error = (wanted[c] - f32::from(palette[index].0[c])) * strength;
+                current[x + 2][c] += error * 7.0 / 16.0;
+                next[x][c] += error * 3.0 / 16.0;
+                next[x + 1][c] += error * 5.0 / 16.0;
+                next[x + 2][c] += error / 16.0;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.fill([0.0; 3]);
+    }
+    indices
+}
+
+fn write_indices(bytes: &mut Vec<u8>, indices: &[usize], bits: u8) {
+    // Indices are packed starting from the least significant bits of a byte
+    let per_byte = 8 / usize::from(bits);
+    for (i, &index) in indices.iter().enumerate() {
         let index = u8::try_from(index).unwrap();
-        // Each index is 4 bits, so we can fit 2 indices in a byte
-        if i % 2 == 0 {
+        let shift = (i % per_byte) * usize::from(bits);
+        if shift == 0 {
             bytes.push(index);
         } else {
-            *bytes.last_mut().unwrap() |= index << 4;
+            *bytes.last_mut().unwrap() |= index << shift;
         }
     }
 }
 
-/// Get a palette of the most frequently used colors in the image
-fn get_palette_freq(img: &Image, palette_size: u16) -> Vec<RgbU8> {
+/// Get a palette of the most frequently used colors in the image. Colors are
+/// grouped into bins with `bin_bits` bits per channel, and each bin is
+/// represented by its mean color
+fn get_palette_freq(
+    pixels: &[RgbU8],
+    palette_size: u16,
+    bin_bits: u8,
+    min_spacing: f32,
+) -> Vec<RgbU8> {
     let palette_size = palette_size.into();
-    let mut palette: Vec<RgbU8> = Vec::with_capacity(palette_size);
 
-    // Group and count colors
-    let mut color_counts = HashMap::new();
-    for pixel in img.pixels() {
-        let key = RgbU8([pixel[0] & 0xf0, pixel[1] & 0xf0, pixel[2] & 0xf0]);
-        let count = color_counts.entry(key).or_insert(0u32);
-        *count += 1;
-    }
-    // Collect and sort in ascending order
-    let mut colors: Vec<_> = color_counts.into_iter().collect();
-    colors.sort_unstable_by(|(_, count1), (_, count2)| count1.cmp(count2));
+    // Group and count colors, then sort in descending order. Ties are broken
+    // by color so that the palette doesn't depend on hash map iteration order
+    let mask = 0xffu8 << (8 - bin_bits);
+    let mut colors = histogram(pixels, |pixel| RgbU8(pixel.0.map(|c| c & mask)));
+    colors.sort_unstable_by_key(|&(color, count)| (std::cmp::Reverse(count), color.0));
 
-    while palette.len() < palette_size {
-        let (color, _) = match colors.pop() {
-            Some(color) => color,
-            None => break,
-        };
+    // Halve the spacing until there are enough colors, or every bin is used
+    let mut min_spacing = min_spacing;
+    loop {
+        let mut palette: Vec<RgbU8> = Vec::with_capacity(palette_size);
+        for &(color, _) in &colors {
+            if palette.len() == palette_size {
+                break;
+            }
+            // Skip color if it's too close to another color in the palette
+            if palette
+                .iter()
+                .any(|p| p.distance2(&color) < min_spacing.powi(2))
+            {
+                continue;
+            }
+            palette.push(color);
+        }
 
-        // Skip color if it's too close to another color in the palette
-        if palette
-            .iter()
-            .any(|p| { p.distance2(&color) } < 32f32.powi(2))
-        {
-            continue;
+        if palette.len() == palette_size.min(colors.len()) || min_spacing == 0.0 {
+            return palette;
         }
+        min_spacing = if min_spacing < 1.0 {
+            0.0
+        } else {
+            min_spacing / 2.0
+        };
+    }
+}
 
-        palette.push(color);
+/// Get a palette by running k-means clustering on the image's colors, starting
+/// from Wu's palette. `rgb` and `pixels` are the same pixels, in RGB and CIELAB
+fn get_palette_k_means(rgb: &[RgbU8], pixels: &[CieLab], palette_size: u16) -> Vec<CieLab> {
+    let centroids = wu::palette(rgb, palette_size.into())
+        .into_iter()
+        .map(|color| Rgb(color.0).into())
+        .collect();
+    // TODO: Compare with CIEDE2000
+    kmeans::fit(pixels, centroids, 0.00005, 250)
+}
+
+/// Get a palette by running median cut on the image's colors, in the color
+/// space of `T`
+fn get_palette_median_cut<T>(pixels: &[RgbU8], palette_size: u16, split: MedianCutSplit) -> Vec<T>
+where
+    T: kmeans::Point<T> + Mul<f32, Output = T> + From<Rgb<u8>>,
+{
+    let colors: Vec<(T, u32)> = histogram(pixels, |&pixel| pixel)
+        .into_iter()
+        .map(|(color, count)| (Rgb(color.0).into(), count))
+        .collect();
+    median_cut::fit(&colors, palette_size.into(), split)
+}
+
+/// Get a palette by building an octree over the image's colors
+fn get_palette_octree(pixels: &[RgbU8], palette_size: u16) -> Vec<RgbU8> {
+    let mut octree = Octree::new();
+    for &pixel in pixels {
+        octree.add(pixel);
     }
+    octree.palette(palette_size.into())
+}
 
-    palette
+/// Groups pixels by key and returns the mean color and number of pixels of
+/// every group, sorted by key
+fn histogram<F>(pixels: &[RgbU8], key: F) -> Vec<(RgbU8, u32)>
+where
+    F: Fn(&RgbU8) -> RgbU8 + Sync + Send,
+{
+    let groups = par::fold_chunks(pixels, HashMap::new, |mut groups, pixel| {
+        let (count, sum) = groups.entry(key(pixel)).or_insert((0u32, [0u64; 3]));
+        *count += 1;
+        for (sum, c) in sum.iter_mut().zip(pixel.0) {
+            *sum += u64::from(c);
+        }
+        groups
+    })
+    .into_iter()
+    .reduce(|mut acc, groups| {
+        for (key, (count, sum)) in groups {
+            let group = acc.entry(key).or_insert((0, [0; 3]));
+            group.0 += count;
+            for (a, b) in group.1.iter_mut().zip(sum) {
+                *a += b;
+            }
+        }
+        acc
+    })
+    .unwrap_or_default();
+
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_unstable_by_key(|&(key, _)| key.0);
+    groups
+        .into_iter()
+        .map(|(_, (count, sum))| {
+            let count64 = u64::from(count);
+            let mean = sum.map(|s| ((s + count64 / 2) / count64) as u8);
+            (RgbU8(mean), count)
+        })
+        .collect()
 }
 
-/// Get a palette by running k-means clustering on the image's colors
-fn get_palette_k_means(pixels: &[CieLab], palette_size: u16) -> Vec<CieLab> {
-    // TODO: Compare with CIEDE2000
-    kmeans::fit(pixels, palette_size.into(), 0.00005, 250)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompress::{decompress, decompress_indexed, decompress_with_palette, DecodeError};
+
+    #[test]
+    fn lossless_round_trip() {
+        for colors in [1, 2, 3, 5, 17, 200, 256] {
+            // Distinct colors scattered over the image
+            let palette: Vec<Rgb<u8>> = (0..colors)
+                .map(|i| Rgb([i as u8, (i * 37) as u8, 255 - (i * 101) as u8]))
+                .collect();
+            let img = Image::from_fn(37, 23, |x, y| {
+                palette[(x * 7 + y * 13 + x * y) as usize % colors]
+            });
+            for options in [
+                Options::default(),
+                Options {
+                    palette_size: 2,
+                    dither: 1.0,
+                    ..Default::default()
+                },
+            ] {
+                let compressed = compress(&img, &options).unwrap();
+                assert!(compressed.lossless, "{} colors", colors);
+                let decoded = decompress(&compressed.bytes).unwrap();
+                assert!(decoded == img, "{} colors", colors);
+            }
+        }
+    }
+
+    #[test]
+    fn lossless_keeps_layout() {
+        // Few enough colors for one exact palette
+        let img = Image::from_fn(20, 20, |x, y| {
+            Rgb([(x % 3) as u8 * 100, (y % 2) as u8 * 200, 0])
+        });
+        for layout in [Layout::Tiles(8, TilePalette::Local), Layout::Blocks(1)] {
+            let options = Options {
+                layout,
+                ..Default::default()
+            };
+            let compressed = compress(&img, &options).unwrap();
+            assert!(!compressed.lossless, "{:?}", layout);
+            let decoded = decompress_indexed(&compressed.bytes).unwrap();
+            assert!(decoded.palettes.len() > 1, "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn tiled_round_trip() {
+        // At most 16 colors per tile and 240 in total, so that neither the
+        // tile palettes nor the shared palette lose any. The size isn't a
+        // multiple of the tile size, to cover the smaller edge tiles
+        let img = Image::from_fn(37, 21, |x, y| {
+            let (tile_x, tile_y) = ((x / 8) as u8, (y / 8) as u8);
+            Rgb([
+                tile_x * 50 + (x % 4) as u8,
+                tile_y * 80 + (y % 4) as u8,
+                128,
+            ])
+        });
+        for tile_palette in [TilePalette::Local, TilePalette::Shared] {
+            let options = Options {
+                freq_bin_bits: 8,
+                freq_min_spacing: 0.0,
+                lossless: false,
+                layout: Layout::Tiles(8, tile_palette),
+                ..Default::default()
+            };
+            let decoded = decompress_indexed(&compress(&img, &options).unwrap().bytes).unwrap();
+            assert_eq!(decoded.palettes.len(), 5 * 3, "{:?}", tile_palette);
+            assert!(decoded.image == img, "{:?}", tile_palette);
+        }
+    }
+
+    #[test]
+    fn referenced_round_trip() {
+        // Together few enough colors for the shared palette to be exact
+        let images = [
+            Image::from_fn(19, 11, |x, y| {
+                Rgb([(x % 10 * 25) as u8, (y % 10 * 25) as u8, 0])
+            }),
+            Image::from_fn(13, 17, |x, y| {
+                Rgb([0, (x % 10 * 25) as u8, (y % 10 * 25) as u8])
+            }),
+        ];
+        let mut counts = ColorCounts::new();
+        for img in &images {
+            counts.add(img);
+        }
+        let fixed_palette: Vec<Rgb<u8>> = shared_palette(&counts, &Options::default())
+            .unwrap()
+            .into_iter()
+            .map(|(color, _)| color)
+            .collect();
+        let options = Options {
+            palette_method: PaletteMethod::Fixed,
+            reference_palette: true,
+            fixed_palette: fixed_palette.clone(),
+            ..Default::default()
+        };
+        for img in &images {
+            let bytes = compress(img, &options).unwrap().bytes;
+            let decoded = decompress_with_palette(&bytes, &fixed_palette).unwrap();
+            assert!(decoded.image == *img);
+            assert!(matches!(
+                decompress(&bytes),
+                Err(DecodeError::MissingPalette(hash)) if hash == palette::hash(&fixed_palette)
+            ));
+            assert!(matches!(
+                decompress_with_palette(&bytes, &fixed_palette[1..]),
+                Err(DecodeError::PaletteMismatch { .. })
+            ));
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn output_does_not_depend_on_thread_count() {
+        // Gradient with some noise, larger than one `par` chunk
+        let img = Image::from_fn(160, 120, |x, y| {
+            let noise = x.wrapping_mul(2_654_435_761) ^ y.wrapping_mul(2_246_822_519);
+            Rgb([
+                (x * 255 / 160) as u8 ^ (noise >> 28) as u8,
+                (y * 255 / 120) as u8 ^ (noise >> 12 & 15) as u8,
+                ((x + y) % 256) as u8,
+            ])
+        });
+        let options = Options {
+            palette_method: PaletteMethod::KMeans,
+            dither: 0.5,
+            ..Default::default()
+        };
+        let compress_with = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| compress(&img, &options).unwrap().bytes)
+        };
+        assert!(compress_with(1) == compress_with(8));
+    }
 }
@@ -0,0 +1,125 @@
+use super::{Compressed, Layout, Options};
+use crate::{decompress::decompress_with_palette, metrics::DeltaE, ColorSpace, Image};
+use clap::ValueEnum;
+
+/// Largest allowed color difference between the input and the decoded image
+#[derive(Debug, Copy, Clone)]
+pub struct QualityTarget {
+    pub max_delta_e: f32,
+    pub statistic: DeltaEStatistic,
+    pub space: ColorSpace,
+}
+
+/// How per-pixel color differences are summarized
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum DeltaEStatistic {
+    Mean,
+    /// 95th percentile
+    P95,
+}
+
+impl DeltaEStatistic {
+    fn get(self, delta_e: &DeltaE) -> f32 {
+        match self {
+            DeltaEStatistic::Mean => delta_e.mean,
+            DeltaEStatistic::P95 => delta_e.p95,
+        }
+    }
+}
+
+/// Finds the smallest palette that meets the target by binary search. Only
+/// the palette size is searched: dithering is left as set, since it spreads
+/// the error over neighbouring pixels and the per-pixel ΔE doesn't improve.
+/// If the target can't be met, returns the result with the lowest error
+pub fn compress(img: &Image, options: &Options, quality: QualityTarget) -> Compressed {
+    let mut base = Options {
+        goal: None,
+        layout: match options.layout {
+            Layout::Blocks(_) => Layout::Global,
+            layout => layout,
+        },
+        ..options.clone()
+    };
+    let measure = |options: &Options| {
+        let compressed = super::compress_checked(img, options);
+        let delta_e = DeltaE::new(
+            img,
+            &decompress_with_palette(&compressed.bytes, &options.fixed_palette)
+                .unwrap()
+                .image,
+            quality.space,
+        );
+        Compressed {
+            delta_e: Some(delta_e),
+            ..compressed
+        }
+    };
+
+    // Images stored exactly have no error at all
+    if options.lossless {
+        let compressed = measure(&base);
+        if compressed.lossless {
+            return compressed;
+        }
+        base.lossless = false;
+    }
+
+    let mut best: Option<(f32, Compressed)> = None;
+    let (mut lo, mut hi) = (1, 256);
+    let mut found = None;
+    while lo <= hi {
+        let palette_size = (lo + hi) / 2;
+        let compressed = measure(&Options {
+            palette_size,
+            ..base.clone()
+        });
+        let error = quality.statistic.get(&compressed.delta_e.unwrap());
+        if error <= quality.max_delta_e {
+            found = Some(compressed);
+            hi = palette_size - 1;
+        } else {
+            if best.as_ref().is_none_or(|&(e, _)| error < e) {
+                best = Some((error, compressed));
+            }
+            lo = palette_size + 1;
+        }
+    }
+
+    found.unwrap_or_else(|| best.unwrap().1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn smallest_palette_meeting_target() {
+        // A smooth gradient with more colors than a palette holds
+        let img = Image::from_fn(64, 48, |x, y| Rgb([(x * 4) as u8, (y * 5) as u8, 90]));
+        let quality = QualityTarget {
+            max_delta_e: 3.0,
+            statistic: DeltaEStatistic::Mean,
+            space: ColorSpace::CieLab,
+        };
+        let options = Options {
+            dither: 0.5,
+            ..Default::default()
+        };
+        let compressed = compress(&img, &options, quality);
+        assert!(compressed.delta_e.unwrap().mean <= quality.max_delta_e);
+        assert_eq!(compressed.dither, options.dither);
+
+        let smaller = crate::compress::compress_checked(
+            &img,
+            &Options {
+                palette_size: compressed.palette_size - 1,
+                lossless: false,
+                ..options
+            },
+        );
+        let decoded = crate::decompress::decompress(&smaller.bytes).unwrap();
+        let delta_e = DeltaE::new(&img, &decoded, quality.space);
+        assert!(delta_e.mean > quality.max_delta_e, "{}", delta_e.mean);
+    }
+}
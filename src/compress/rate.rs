@@ -0,0 +1,100 @@
+use super::{unique_colors, Compressed, Layout, Options};
+use crate::{color::RgbU8, decompress::decompress_with_palette, metrics::mse, Image, TilePalette};
+
+/// Size budget for a compressed file
+#[derive(Debug, Copy, Clone)]
+pub enum Target {
+    /// Total file size in bytes
+    Bytes(usize),
+    /// File size in bits per pixel
+    BitsPerPixel(f32),
+}
+
+impl Target {
+    fn bytes(self, img: &Image) -> usize {
+        match self {
+            Target::Bytes(bytes) => bytes,
+            Target::BitsPerPixel(bpp) => {
+                let pixels = f64::from(img.width()) * f64::from(img.height());
+                (f64::from(bpp) * pixels / 8.0) as usize
+            }
+        }
+    }
+}
+
+const DITHER_STRENGTHS: [f32; 3] = [0.0, 0.5, 1.0];
+/// Tiles tried besides a single palette
+const TILE_SIZE: u16 = 32;
+
+/// For every combination of dithering strength and tiling, finds the largest
+/// palette that fits the budget by binary search, and returns the result with
+/// the lowest mean squared error. If nothing fits, returns the smallest file
+pub fn compress(img: &Image, options: &Options, target: Target) -> Compressed {
+    let budget = target.bytes(img);
+    let mut base = Options {
+        goal: None,
+        layout: Layout::Global,
+        ..options.clone()
+    };
+    let tile_palette = match options.layout {
+        Layout::Tiles(_, tile_palette) => tile_palette,
+        _ => TilePalette::Local,
+    };
+
+    // Nothing beats an exact copy
+    if options.lossless {
+        let rgb: Vec<RgbU8> = img.pixels().map(|&p| p.into()).collect();
+        if unique_colors(&rgb).is_some() {
+            let compressed = super::compress_checked(img, &base);
+            if compressed.bytes.len() <= budget {
+                return compressed;
+            }
+        }
+        base.lossless = false;
+    }
+
+    let mut best: Option<(f64, Compressed)> = None;
+    let mut smallest: Option<Compressed> = None;
+    for layout in [Layout::Global, Layout::Tiles(TILE_SIZE, tile_palette)] {
+        for dither in DITHER_STRENGTHS {
+            let (mut lo, mut hi) = (1, 256);
+            let mut fit = None;
+            while lo <= hi {
+                let palette_size = (lo + hi) / 2;
+                let candidate = Options {
+                    palette_size,
+                    layout,
+                    dither,
+                    ..base.clone()
+                };
+                let compressed = super::compress_checked(img, &candidate);
+                if compressed.bytes.len() <= budget {
+                    fit = Some(compressed);
+                    lo = palette_size + 1;
+                } else {
+                    if smallest
+                        .as_ref()
+                        .is_none_or(|s| compressed.bytes.len() < s.bytes.len())
+                    {
+                        smallest = Some(compressed);
+                    }
+                    hi = palette_size - 1;
+                }
+            }
+
+            if let Some(compressed) = fit {
+                let error = mse(
+                    img,
+                    &decompress_with_palette(&compressed.bytes, &options.fixed_palette)
+                        .unwrap()
+                        .image,
+                );
+                if best.as_ref().is_none_or(|&(e, _)| error < e) {
+                    best = Some((error, compressed));
+                }
+            }
+        }
+    }
+
+    best.map(|(_, compressed)| compressed).or(smallest).unwrap()
+}
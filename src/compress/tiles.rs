@@ -0,0 +1,60 @@
+use super::{quantize, write_indices, GlobalPalette, Layout, Options, PaletteCoding};
+use crate::{color::RgbU8, index_bits, par, tiles, Image, TilePalette};
+
+/// Number of colors in the global palette that shared tile palettes refer to
+const GLOBAL_PALETTE_SIZE: u16 = 256;
+
+/// Writes every tile's palette and indices, after the global palette if the
+/// tile palettes use one
+pub fn write(
+    bytes: &mut Vec<u8>,
+    img: &Image,
+    rgb: &[RgbU8],
+    tile_size: u16,
+    tile_palette: TilePalette,
+    options: &Options,
+) {
+    assert!(tile_size > 0, "tile size must be positive");
+    bytes.extend_from_slice(&tile_size.to_le_bytes());
+    bytes.push(tile_palette as u8);
+
+    let global = match tile_palette {
+        TilePalette::Local => None,
+        TilePalette::Shared => {
+            let global_options = Options {
+                palette_size: GLOBAL_PALETTE_SIZE,
+                layout: Layout::Global,
+                ..options.clone()
+            };
+            let width = usize::try_from(img.width()).unwrap();
+            let global = quantize(rgb, width, &global_options, &PaletteCoding::Rgb);
+            bytes.extend_from_slice(&u32::try_from(global.palette.len()).unwrap().to_le_bytes());
+            bytes.extend_from_slice(&global.coded);
+            Some(GlobalPalette::new(global.palette))
+        }
+    };
+    let coding = match &global {
+        Some(global) => PaletteCoding::Shared(global),
+        None => PaletteCoding::Rgb,
+    };
+
+    let width = usize::try_from(img.width()).unwrap();
+    let tiles: Vec<_> = tiles(img.width(), img.height(), tile_size.into()).collect();
+    let tiles = par::map(&tiles, |&(x, y, w, h)| {
+        let [x, y, w, h] = [x, y, w, h].map(|v| usize::try_from(v).unwrap());
+        let pixels: Vec<RgbU8> = (y..y + h)
+            .flat_map(|row| &rgb[row * width + x..row * width + x + w])
+            .copied()
+            .collect();
+
+        let quantized = quantize(&pixels, w, options, &coding);
+        let palette_size = quantized.palette.len();
+        let mut bytes = vec![u8::try_from(palette_size - 1).unwrap()];
+        bytes.extend_from_slice(&quantized.coded);
+        write_indices(&mut bytes, &quantized.indices, index_bits(palette_size));
+        bytes
+    });
+    for tile in tiles {
+        bytes.extend_from_slice(&tile);
+    }
+}
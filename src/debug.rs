@@ -0,0 +1,129 @@
+//! Images for inspecting compression results
+
+use crate::{color::CieLab, decompress::Indexed, Distance, Image};
+use image::Rgb;
+use std::collections::HashSet;
+
+/// Side of a palette swatch cell in pixels
+const SWATCH_CELL: u32 = 16;
+const SWATCH_COLUMNS: u32 = 16;
+
+/// Draws the CIELAB ΔE of every pixel from black through red and yellow to
+/// white. Differences of `max_delta_e` and above are white
+pub fn heat_map(original: &Image, decoded: &Image, max_delta_e: f32) -> Image {
+    assert_eq!(
+        original.dimensions(),
+        decoded.dimensions(),
+        "images differ in size"
+    );
+    let mut img = Image::new(original.width(), original.height());
+    for ((pixel, a), b) in img
+        .pixels_mut()
+        .zip(original.pixels())
+        .zip(decoded.pixels())
+    {
+        let delta_e = CieLab::from(*a).distance(&CieLab::from(*b));
+        *pixel = heat((delta_e / max_delta_e).clamp(0.0, 1.0));
+    }
+    img
+}
+
+/// Maps 0..=1 to black, red, yellow, white
+fn heat(t: f32) -> Rgb<u8> {
+    let channel = |start: f32| ((t * 3.0 - start).clamp(0.0, 1.0) * 255.0).round() as u8;
+    Rgb([channel(0.0), channel(1.0), channel(2.0)])
+}
+
+/// Draws every distinct color of the palettes in order of first appearance,
+/// 16 per row
+pub fn palette_swatch(palettes: &[Vec<Rgb<u8>>]) -> Image {
+    let mut colors: Vec<Rgb<u8>> = Vec::new();
+    let mut seen = HashSet::new();
+    for &color in palettes.iter().flatten() {
+        if seen.insert(color.0) {
+            colors.push(color);
+        }
+    }
+
+    // Block mode can produce thousands of colors, keep the image roughly
+    // square
+    let columns = SWATCH_COLUMNS.max((colors.len() as f64).sqrt().ceil() as u32);
+    let rows = (colors.len() as u32).div_ceil(columns).max(1);
+    let mut img = Image::new(columns * SWATCH_CELL, rows * SWATCH_CELL);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let i = (y / SWATCH_CELL * columns + x / SWATCH_CELL) as usize;
+        if let Some(&color) = colors.get(i) {
+            *pixel = color;
+        }
+    }
+    img
+}
+
+/// Draws every palette index in its own false color, so that neighbouring
+/// indices are easy to tell apart
+pub fn index_map(indexed: &Indexed) -> Image {
+    let (width, height) = indexed.image.dimensions();
+    let colors: Vec<Rgb<u8>> = (0..=u8::MAX).map(false_color).collect();
+    Image::from_fn(width, height, |x, y| {
+        colors[usize::from(indexed.indices[(y * width + x) as usize])]
+    })
+}
+
+/// Steps around the hue circle by the golden angle
+fn false_color(index: u8) -> Rgb<u8> {
+    const GOLDEN: f32 = 0.618_034;
+    let hue = (f32::from(index) * GOLDEN).fract() * 6.0;
+    let f = hue.fract();
+    let (r, g, b) = match hue as u8 {
+        0 => (1.0, f, 0.0),
+        1 => (1.0 - f, 1.0, 0.0),
+        2 => (0.0, 1.0, f),
+        3 => (0.0, 1.0 - f, 1.0),
+        4 => (f, 0.0, 1.0),
+        _ => (1.0, 0.0, 1.0 - f),
+    };
+    Rgb([r, g, b].map(|c: f32| (c * 255.0).round() as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heat_map_range() {
+        let black = Image::new(5, 3);
+        let white = Image::from_pixel(5, 3, Rgb([255; 3]));
+        assert!(heat_map(&black, &black, 10.0)
+            .pixels()
+            .all(|p| p.0 == [0; 3]));
+        assert!(heat_map(&black, &white, 10.0)
+            .pixels()
+            .all(|p| p.0 == [255; 3]));
+    }
+
+    #[test]
+    fn swatch_drops_repeated_colors() {
+        let (red, green) = (Rgb([255, 0, 0]), Rgb([0, 255, 0]));
+        let img = palette_swatch(&[vec![red, green], vec![green, red]]);
+        assert_eq!(
+            img.dimensions(),
+            (SWATCH_COLUMNS * SWATCH_CELL, SWATCH_CELL)
+        );
+        assert_eq!(*img.get_pixel(0, 0), red);
+        assert_eq!(*img.get_pixel(SWATCH_CELL, 0), green);
+        assert_eq!(*img.get_pixel(2 * SWATCH_CELL, 0), Rgb([0; 3]));
+    }
+
+    #[test]
+    fn index_map_colors_are_distinct() {
+        let indices: Vec<u8> = (0..=u8::MAX).collect();
+        let indexed = Indexed {
+            image: Image::new(16, 16),
+            palettes: Vec::new(),
+            indices,
+        };
+        let img = index_map(&indexed);
+        let colors: HashSet<[u8; 3]> = img.pixels().map(|p| p.0).collect();
+        assert_eq!(colors.len(), 256);
+    }
+}
@@ -1,41 +1,381 @@
-use crate::Image;
+use crate::{
+    block::{self, BLOCK_SIZE},
+    index_bits, palette, tiles, Image, Mode, TilePalette, LEGACY_INDEX_BITS, MAGIC, VERSION,
+};
 use image::Rgb;
+use libflate::deflate::Decoder;
+use std::fmt;
+use std::io::{self, Read};
 use std::ops::{BitOrAssign, Shl};
 
-pub fn decompress(bytes: &[u8]) -> Image {
-    let mut bytes = bytes.iter().copied().peekable();
-    let width: u32 = read(&mut bytes);
-    let height: u32 = read(&mut bytes);
+/// Why a file couldn't be decompressed
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The deflate stream is invalid
+    Inflate(io::Error),
+    UnsupportedVersion(u8),
+    UnknownMode(u8),
+    UnknownTilePalette(u8),
+    InvalidBlockBits(u8),
+    InvalidTileSize,
+    /// A pixel refers to a color past the end of its palette
+    InvalidIndex,
+    /// The file ends before all pixels are read
+    Truncated,
+    /// The file refers to a palette with this hash, which wasn't given
+    MissingPalette(u64),
+    /// The given palette isn't the one the file refers to
+    PaletteMismatch {
+        expected: u64,
+        found: u64,
+    },
+}
 
-    let palette_size = read::<u32>(&mut bytes).try_into().unwrap();
-    let mut palette: Vec<Rgb<u8>> = Vec::with_capacity(palette_size);
-    for _ in 0..palette_size {
-        let color = Rgb([read(&mut bytes), read(&mut bytes), read(&mut bytes)]);
-        palette.push(color);
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Inflate(err) => write!(f, "invalid deflate stream: {}", err),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported version {}", version)
+            }
+            DecodeError::UnknownMode(mode) => write!(f, "unknown mode {}", mode),
+            DecodeError::UnknownTilePalette(coding) => {
+                write!(f, "unknown tile palette coding {}", coding)
+            }
+            DecodeError::InvalidBlockBits(bits) => {
+                write!(f, "block index bits must be 1 or 2, got {}", bits)
+            }
+            DecodeError::InvalidTileSize => write!(f, "tile size must not be 0"),
+            DecodeError::InvalidIndex => write!(f, "palette index out of range"),
+            DecodeError::Truncated => write!(f, "file is truncated"),
+            DecodeError::MissingPalette(hash) => {
+                write!(f, "file needs the palette with hash {:016x}", hash)
+            }
+            DecodeError::PaletteMismatch { expected, found } => write!(
+                f,
+                "file needs the palette with hash {:016x}, got {:016x}",
+                expected, found
+            ),
+        }
     }
+}
 
-    let mut img = Image::new(width, height);
-    for (i, pixel) in img.pixels_mut().enumerate() {
-        // TODO: Handle palatte sizes other than 16
-        let index = if i % 2 == 0 {
-            *bytes.peek().unwrap() & 0b00001111
-        } else {
-            bytes.next().unwrap() >> 4
-        };
-        *pixel = palette[usize::from(index)];
+impl std::error::Error for DecodeError {}
+
+/// What the header of a file says about its layout
+#[derive(Debug, Copy, Clone)]
+pub struct Header {
+    /// `None` for files from before the format was versioned
+    pub version: Option<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub codec: Codec,
+}
+
+/// How the pixels of a file are stored
+#[derive(Debug, Copy, Clone)]
+pub enum Codec {
+    /// One palette for the whole image
+    Global,
+    /// A palette per tile
+    Tiled {
+        tile_size: u16,
+        palette: TilePalette,
+    },
+    /// Two endpoint colors and 1 or 2 bits per pixel for every 4x4 block
+    Block { bits: u8 },
+    /// One palette for the whole image, stored outside the file
+    Referenced {
+        palette_hash: u64,
+        palette_size: u32,
+    },
+}
+
+/// A decoded image along with the palette index of every pixel
+pub struct Indexed {
+    pub image: Image,
+    /// One palette for global and referenced mode, otherwise one per tile or
+    /// block in row-major order
+    pub palettes: Vec<Vec<Rgb<u8>>>,
+    /// Index of every pixel into the palette of its tile or block
+    pub indices: Vec<u8>,
+}
+
+/// The contents of a file, short of the pixel colors
+pub(crate) struct Parsed {
+    pub header: Header,
+    /// Palette the tile palettes refer to. Empty unless tile palettes are
+    /// shared
+    pub global: Vec<Rgb<u8>>,
+    /// Empty for referenced mode when the palette isn't given
+    pub palettes: Vec<Vec<Rgb<u8>>>,
+    pub indices: Vec<u8>,
+}
+
+/// Decompresses an imgcpr file
+pub fn decompress(bytes: &[u8]) -> Result<Image, DecodeError> {
+    Ok(decompress_indexed(bytes)?.image)
+}
+
+/// Decompresses an imgcpr file, keeping the palettes and indices
+pub fn decompress_indexed(bytes: &[u8]) -> Result<Indexed, DecodeError> {
+    decode(bytes, None)
+}
+
+/// Decompresses an imgcpr file that may refer to `palette` instead of
+/// storing its own. Files storing their palettes are decoded as usual
+pub fn decompress_with_palette(bytes: &[u8], palette: &[Rgb<u8>]) -> Result<Indexed, DecodeError> {
+    decode(bytes, Some(palette))
+}
+
+fn decode(bytes: &[u8], palette: Option<&[Rgb<u8>]>) -> Result<Indexed, DecodeError> {
+    let parsed = parse(&inflate(bytes)?, palette)?;
+    if let (Codec::Referenced { palette_hash, .. }, None) = (parsed.header.codec, palette) {
+        return Err(DecodeError::MissingPalette(palette_hash));
     }
+    Ok(Indexed {
+        image: paint(&parsed),
+        palettes: parsed.palettes,
+        indices: parsed.indices,
+    })
+}
+
+/// Undoes the entropy coding
+pub(crate) fn inflate(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut decoder = Decoder::new(bytes);
+    let mut bytes = Vec::new();
+    decoder
+        .read_to_end(&mut bytes)
+        .map_err(DecodeError::Inflate)?;
+    Ok(bytes)
+}
+
+/// Reads the header, palettes and indices from the file contents after
+/// entropy decoding. `external` is the palette of referenced mode, which is
+/// left empty without it
+pub(crate) fn parse(bytes: &[u8], external: Option<&[Rgb<u8>]>) -> Result<Parsed, DecodeError> {
+    // Files from before the format was versioned have no header
+    let versioned = bytes.starts_with(&MAGIC);
+    let mut bytes = bytes.iter().copied();
+    let (version, mode) = if versioned {
+        bytes.nth(MAGIC.len() - 1);
+        let version: u8 = read(&mut bytes)?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let mode = Mode::try_from(read::<u8>(&mut bytes)?).map_err(DecodeError::UnknownMode)?;
+        (Some(version), mode)
+    } else {
+        (None, Mode::Global)
+    };
+    let width: u32 = read(&mut bytes)?;
+    let height: u32 = read(&mut bytes)?;
+    // Every pixel takes at least one bit of the rest of the file, so check
+    // the untrusted dimensions against it before allocating
+    let pixel_count = u64::from(width) * u64::from(height);
+    if pixel_count.div_ceil(8) > bytes.len() as u64 {
+        return Err(DecodeError::Truncated);
+    }
+    let pixel_count = usize::try_from(pixel_count).map_err(|_| DecodeError::Truncated)?;
+    let mut global = Vec::new();
+    let mut palettes = Vec::new();
+    let mut pixel_indices = vec![0u8; pixel_count];
+    let mut put = |x: u32, y: u32, index: usize, palette: &[Rgb<u8>]| {
+        if index >= palette.len() {
+            return Err(DecodeError::InvalidIndex);
+        }
+        pixel_indices[y as usize * width as usize + x as usize] = index as u8;
+        Ok(())
+    };
+
+    let codec = match mode {
+        Mode::Global => {
+            let palette_size = read::<u32>(&mut bytes)?.try_into().unwrap();
+            let palette = read_colors(&mut bytes, palette_size)?;
+            // Indices were 4 bits wide before their width followed the
+            // palette size
+            let bits = match version {
+                Some(_) => index_bits(palette_size),
+                None => LEGACY_INDEX_BITS,
+            };
+            let indices = read_indices(&mut bytes, pixel_count, bits)?;
+            for (i, index) in (0..).zip(indices) {
+                put(i % width, i / width, index, &palette)?;
+            }
+            palettes.push(palette);
+            Codec::Global
+        }
+        Mode::Tiled => {
+            let tile_size: u16 = read(&mut bytes)?;
+            if tile_size == 0 {
+                return Err(DecodeError::InvalidTileSize);
+            }
+            let coding = TilePalette::try_from(read::<u8>(&mut bytes)?)
+                .map_err(DecodeError::UnknownTilePalette)?;
+            if coding == TilePalette::Shared {
+                let palette_size = read::<u32>(&mut bytes)?.try_into().unwrap();
+                global = read_colors(&mut bytes, palette_size)?;
+            }
+            let global_color = |index: u8| {
+                global
+                    .get(usize::from(index))
+                    .copied()
+                    .ok_or(DecodeError::InvalidIndex)
+            };
+
+            for (x, y, w, h) in tiles(width, height, tile_size.into()) {
+                let palette_size = usize::from(read::<u8>(&mut bytes)?) + 1;
+                let palette: Vec<Rgb<u8>> = match coding {
+                    TilePalette::Local => read_colors(&mut bytes, palette_size)?,
+                    TilePalette::Shared => (0..palette_size)
+                        .map(|_| global_color(read(&mut bytes)?))
+                        .collect::<Result<_, _>>()?,
+                };
+
+                let count = usize::try_from(w * h).unwrap();
+                let indices = read_indices(&mut bytes, count, index_bits(palette_size))?;
+                for (i, index) in (0..).zip(indices) {
+                    put(x + i % w, y + i / w, index, &palette)?;
+                }
+                palettes.push(palette);
+            }
+            Codec::Tiled {
+                tile_size,
+                palette: coding,
+            }
+        }
+        Mode::Block => {
+            let bits: u8 = read(&mut bytes)?;
+            if !(1..=2).contains(&bits) {
+                return Err(DecodeError::InvalidBlockBits(bits));
+            }
+            let mask = (1 << bits) - 1;
+            for (x, y, w, h) in tiles(width, height, BLOCK_SIZE) {
+                let c0: u16 = read(&mut bytes)?;
+                let c1: u16 = read(&mut bytes)?;
+                let indices: u32 = match bits {
+                    1 => read::<u16>(&mut bytes)?.into(),
+                    _ => read(&mut bytes)?,
+                };
+                let palette: Vec<Rgb<u8>> = block::palette(c0, c1, bits)
+                    .into_iter()
+                    .map(|color| Rgb(color.0))
+                    .collect();
+                for (px, py) in (0..h).flat_map(|py| (0..w).map(move |px| (px, py))) {
+                    let index = (indices >> ((py * BLOCK_SIZE + px) * u32::from(bits))) & mask;
+                    put(x + px, y + py, index as usize, &palette)?;
+                }
+                palettes.push(palette);
+            }
+            Codec::Block { bits }
+        }
+        Mode::Referenced => {
+            let palette_hash: u64 = read(&mut bytes)?;
+            let palette_size: u32 = read(&mut bytes)?;
+            let palette = match external {
+                Some(palette) => {
+                    let found = palette::hash(palette);
+                    if found != palette_hash || palette.len() != palette_size as usize {
+                        return Err(DecodeError::PaletteMismatch {
+                            expected: palette_hash,
+                            found,
+                        });
+                    }
+                    palette.to_vec()
+                }
+                None => Vec::new(),
+            };
+            let size = palette_size as usize;
+            let indices = read_indices(&mut bytes, pixel_count, index_bits(size))?;
+            for (i, index) in indices.into_iter().enumerate() {
+                if index >= size {
+                    return Err(DecodeError::InvalidIndex);
+                }
+                pixel_indices[i] = index as u8;
+            }
+            palettes.push(palette);
+            Codec::Referenced {
+                palette_hash,
+                palette_size,
+            }
+        }
+    };
+
+    Ok(Parsed {
+        header: Header {
+            version,
+            width,
+            height,
+            codec,
+        },
+        global,
+        palettes,
+        indices: pixel_indices,
+    })
+}
+
+/// Looks up the color of every pixel in the palette of its tile
+fn paint(parsed: &Parsed) -> Image {
+    let Header {
+        width,
+        height,
+        codec,
+        ..
+    } = parsed.header;
+    let tile_size = match codec {
+        Codec::Global | Codec::Referenced { .. } => width.max(height).max(1),
+        Codec::Tiled { tile_size, .. } => tile_size.into(),
+        Codec::Block { .. } => BLOCK_SIZE,
+    };
 
+    let mut img = Image::new(width, height);
+    for ((x, y, w, h), palette) in tiles(width, height, tile_size).zip(&parsed.palettes) {
+        for (px, py) in (0..h).flat_map(|py| (0..w).map(move |px| (px, py))) {
+            let (x, y) = (x + px, y + py);
+            let index = parsed.indices[y as usize * width as usize + x as usize];
+            img.put_pixel(x, y, palette[usize::from(index)]);
+        }
+    }
     img
 }
 
-fn read<T>(bytes: &mut dyn Iterator<Item = u8>) -> T
+fn read_colors(
+    bytes: &mut dyn Iterator<Item = u8>,
+    count: usize,
+) -> Result<Vec<Rgb<u8>>, DecodeError> {
+    (0..count)
+        .map(|_| Ok(Rgb([read(bytes)?, read(bytes)?, read(bytes)?])))
+        .collect()
+}
+
+/// Reads `count` indices of `bits` bits each. Indices are packed starting
+/// from the least significant bits of a byte, and the last byte is padded
+fn read_indices(
+    bytes: &mut dyn Iterator<Item = u8>,
+    count: usize,
+    bits: u8,
+) -> Result<Vec<usize>, DecodeError> {
+    let per_byte = 8 / usize::from(bits);
+    let mask = u8::MAX >> (8 - bits);
+    let mut indices = Vec::with_capacity(count);
+    for _ in 0..count.div_ceil(per_byte) {
+        let byte: u8 = read(bytes)?;
+        for i in 0..per_byte {
+            indices.push(usize::from((byte >> (i * usize::from(bits))) & mask));
+        }
+    }
+    indices.truncate(count);
+    Ok(indices)
+}
+
+fn read<T>(bytes: &mut dyn Iterator<Item = u8>) -> Result<T, DecodeError>
 where
     T: Default + Copy + From<u8> + Shl<usize, Output = T> + BitOrAssign<T>,
 {
     let mut value = T::default();
     for i in 0..std::mem::size_of::<T>() {
-        let byte: T = bytes.next().unwrap_or(0).into();
+        let byte: T = bytes.next().ok_or(DecodeError::Truncated)?.into();
         value |= byte << (i * 8);
     }
-    value
+    Ok(value)
 }
@@ -0,0 +1,143 @@
+//! Writes palette images to standard formats, keeping the palette as is
+
+use crate::{decompress::Indexed, index_bits};
+use image::Rgb;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+
+/// An image stored as one palette of at most 256 colors and the palette
+/// index of every pixel
+#[derive(Debug, Clone)]
+pub struct Paletted {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Vec<Rgb<u8>>,
+    /// Row-major palette indices
+    pub indices: Vec<u8>,
+}
+
+/// Why an image couldn't be exported
+#[derive(Debug)]
+pub enum ExportError {
+    Png(png::EncodingError),
+    Gif(gif::EncodingError),
+    /// GIF images are limited to 65535 pixels on each side
+    TooLarge,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::Png(err) => write!(f, "cannot encode PNG: {}", err),
+            ExportError::Gif(err) => write!(f, "cannot encode GIF: {}", err),
+            ExportError::TooLarge => write!(f, "image is too large for GIF"),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<png::EncodingError> for ExportError {
+    fn from(err: png::EncodingError) -> Self {
+        ExportError::Png(err)
+    }
+}
+
+impl From<gif::EncodingError> for ExportError {
+    fn from(err: gif::EncodingError) -> Self {
+        ExportError::Gif(err)
+    }
+}
+
+impl Paletted {
+    /// Uses the palette of a decoded image, or merges the palettes of its
+    /// tiles or blocks. Returns `None` if they have more than 256 colors
+    /// together
+    pub fn from_indexed(indexed: &Indexed) -> Option<Self> {
+        let (width, height) = indexed.image.dimensions();
+        if let [palette] = &indexed.palettes[..] {
+            return Some(Paletted {
+                width,
+                height,
+                palette: palette.clone(),
+                indices: indexed.indices.clone(),
+            });
+        }
+
+        let mut palette = Vec::new();
+        let mut lookup: HashMap<[u8; 3], u8> = HashMap::new();
+        let mut indices = Vec::with_capacity(indexed.indices.len());
+        for &color in indexed.image.pixels() {
+            let index = match lookup.get(&color.0) {
+                Some(&index) => index,
+                None => {
+                    let index = u8::try_from(palette.len()).ok()?;
+                    lookup.insert(color.0, index);
+                    palette.push(color);
+                    index
+                }
+            };
+            indices.push(index);
+        }
+        Some(Paletted {
+            width,
+            height,
+            palette,
+            indices,
+        })
+    }
+
+    fn flat_palette(&self) -> Vec<u8> {
+        self.palette.iter().flat_map(|color| color.0).collect()
+    }
+
+    /// Writes an indexed-color PNG with the smallest bit depth that fits the
+    /// palette
+    pub fn write_png<W: Write>(&self, w: W) -> Result<(), ExportError> {
+        let bits = index_bits(self.palette.len());
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(match bits {
+            1 => png::BitDepth::One,
+            2 => png::BitDepth::Two,
+            4 => png::BitDepth::Four,
+            _ => png::BitDepth::Eight,
+        });
+        encoder.set_palette(self.flat_palette());
+
+        // Rows are packed starting from the most significant bits, and padded
+        // to whole bytes
+        let per_byte = 8 / usize::from(bits);
+        let width = self.width as usize;
+        let mut data = Vec::with_capacity(width.div_ceil(per_byte) * self.height as usize);
+        for row in self.indices.chunks(width.max(1)) {
+            for pixels in row.chunks(per_byte) {
+                let byte = pixels.iter().enumerate().fold(0u8, |byte, (i, &index)| {
+                    byte | index << (8 - usize::from(bits) * (i + 1))
+                });
+                data.push(byte);
+            }
+        }
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Writes a single frame GIF with the palette as its global color table
+    pub fn write_gif<W: Write>(&self, w: W) -> Result<(), ExportError> {
+        let width = u16::try_from(self.width).map_err(|_| ExportError::TooLarge)?;
+        let height = u16::try_from(self.height).map_err(|_| ExportError::TooLarge)?;
+        let mut encoder = gif::Encoder::new(w, width, height, &self.flat_palette())?;
+        encoder.write_frame(&gif::Frame {
+            width,
+            height,
+            buffer: Cow::Borrowed(&self.indices),
+            ..gif::Frame::default()
+        })?;
+        Ok(())
+    }
+}
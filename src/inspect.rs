@@ -0,0 +1,224 @@
+//! Reads the structure of an imgcpr file without decoding its pixels
+
+use crate::decompress::{inflate, parse, Codec, DecodeError, Header};
+use clap::ValueEnum;
+use image::Rgb;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// What a file contains
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub header: Header,
+    /// Size of the file
+    pub compressed_size: usize,
+    /// Size of the file after entropy decoding
+    pub uncompressed_size: usize,
+    /// Number of palettes, one per tile or block except in global mode
+    pub palette_count: usize,
+    /// The palette shared by the whole image. Without one, every distinct
+    /// color of the tile or block palettes in order of first appearance.
+    /// Empty in referenced mode
+    pub palette: Vec<Rgb<u8>>,
+    /// How many pixels use each palette index
+    pub index_histogram: Vec<u64>,
+}
+
+impl Info {
+    pub fn pixel_count(&self) -> u64 {
+        u64::from(self.header.width) * u64::from(self.header.height)
+    }
+
+    /// Size of the pixels stored as 24-bit RGB
+    pub fn raw_size(&self) -> u64 {
+        self.pixel_count() * 3
+    }
+
+    pub fn bits_per_pixel(&self) -> f64 {
+        (self.compressed_size * 8) as f64 / self.pixel_count().max(1) as f64
+    }
+
+    /// Returns the info as a JSON object
+    pub fn to_json(&self) -> String {
+        let Header {
+            version,
+            width,
+            height,
+            codec,
+        } = self.header;
+        let mut json = String::from("{");
+        match version {
+            Some(version) => write!(json, "\"version\":{},", version),
+            None => write!(json, "\"version\":null,"),
+        }
+        .unwrap();
+        write!(json, "\"width\":{},\"height\":{},", width, height).unwrap();
+        match codec {
+            Codec::Global => write!(json, "\"codec\":{{\"mode\":\"global\"}},"),
+            Codec::Tiled { tile_size, palette } => write!(
+                json,
+                "\"codec\":{{\"mode\":\"tiled\",\"tile_size\":{},\"tile_palette\":\"{}\"}},",
+                tile_size,
+                palette.to_possible_value().unwrap().get_name()
+            ),
+            Codec::Block { bits } => {
+                write!(json, "\"codec\":{{\"mode\":\"block\",\"bits\":{}}},", bits)
+            }
+            Codec::Referenced {
+                palette_hash,
+                palette_size,
+            } => write!(
+                json,
+                "\"codec\":{{\"mode\":\"referenced\",\"palette_hash\":\"{:016x}\",\"palette_size\":{}}},",
+                palette_hash, palette_size
+            ),
+        }
+        .unwrap();
+        write!(
+            json,
+            "\"compressed_size\":{},\"uncompressed_size\":{},\"raw_size\":{},\"bits_per_pixel\":{},",
+            self.compressed_size,
+            self.uncompressed_size,
+            self.raw_size(),
+            self.bits_per_pixel()
+        )
+        .unwrap();
+        write!(
+            json,
+            "\"palette_count\":{},\"palette\":[",
+            self.palette_count
+        )
+        .unwrap();
+        for (i, color) in self.palette.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(json, "{}\"{}\"", separator, hex(*color)).unwrap();
+        }
+        json.push_str("],\"index_histogram\":[");
+        for (i, count) in self.index_histogram.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(json, "{}{}", separator, count).unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Formats a color as `#rrggbb`
+pub fn hex(color: Rgb<u8>) -> String {
+    let [r, g, b] = color.0;
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Parses an imgcpr file
+pub fn inspect(bytes: &[u8]) -> Result<Info, DecodeError> {
+    let contents = inflate(bytes)?;
+    let parsed = parse(&contents, None)?;
+
+    let palette = match parsed.header.codec {
+        Codec::Global | Codec::Referenced { .. } => parsed.palettes[0].clone(),
+        _ if !parsed.global.is_empty() => parsed.global,
+        _ => {
+            let mut seen = HashSet::new();
+            parsed
+                .palettes
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|color| seen.insert(color.0))
+                .collect()
+        }
+    };
+
+    let histogram_len = match parsed.header.codec {
+        // Indices are bytes, whatever the untrusted palette size says
+        Codec::Referenced { palette_size, .. } => (palette_size as usize).min(256),
+        _ => parsed.palettes.iter().map(Vec::len).max().unwrap_or(0),
+    };
+    let mut index_histogram = vec![0; histogram_len];
+    for &index in &parsed.indices {
+        index_histogram[usize::from(index)] += 1;
+    }
+
+    Ok(Info {
+        header: parsed.header,
+        compressed_size: bytes.len(),
+        uncompressed_size: contents.len(),
+        palette_count: parsed.palettes.len(),
+        palette,
+        index_histogram,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compress::{compress, Layout, Options},
+        Image, PaletteMethod, TilePalette,
+    };
+
+    fn image() -> Image {
+        Image::from_fn(20, 12, |x, y| {
+            Rgb([(x % 4 * 60) as u8, (y % 3 * 90) as u8, 7])
+        })
+    }
+
+    #[test]
+    fn global() {
+        let img = image();
+        let bytes = compress(&img, &Options::default()).unwrap().bytes;
+        let info = inspect(&bytes).unwrap();
+        assert!(matches!(info.header.codec, Codec::Global));
+        assert_eq!((info.header.width, info.header.height), (20, 12));
+        assert_eq!(info.compressed_size, bytes.len());
+        assert_eq!(info.palette_count, 1);
+        assert_eq!(info.palette.len(), 12);
+        assert_eq!(info.index_histogram.len(), 12);
+        assert_eq!(info.index_histogram.iter().sum::<u64>(), info.pixel_count());
+        assert!(info.to_json().contains("\"codec\":{\"mode\":\"global\"}"));
+    }
+
+    #[test]
+    fn tiled() {
+        let options = Options {
+            layout: Layout::Tiles(8, TilePalette::Local),
+            ..Default::default()
+        };
+        let info = inspect(&compress(&image(), &options).unwrap().bytes).unwrap();
+        assert!(matches!(
+            info.header.codec,
+            Codec::Tiled {
+                tile_size: 8,
+                palette: TilePalette::Local
+            }
+        ));
+        assert_eq!(info.palette_count, 3 * 2);
+        assert_eq!(info.index_histogram.iter().sum::<u64>(), info.pixel_count());
+        assert!(info.to_json().contains("\"tile_palette\":\"local\""));
+    }
+
+    #[test]
+    fn referenced() {
+        let fixed_palette = vec![Rgb([0, 0, 0]), Rgb([255, 255, 255]), Rgb([255, 0, 0])];
+        let options = Options {
+            palette_method: PaletteMethod::Fixed,
+            fixed_palette: fixed_palette.clone(),
+            reference_palette: true,
+            ..Default::default()
+        };
+        let info = inspect(&compress(&image(), &options).unwrap().bytes).unwrap();
+        match info.header.codec {
+            Codec::Referenced {
+                palette_hash,
+                palette_size,
+            } => {
+                assert_eq!(palette_hash, crate::palette::hash(&fixed_palette));
+                assert_eq!(palette_size, 3);
+            }
+            codec => panic!("{:?}", codec),
+        }
+        assert!(info.palette.is_empty());
+        assert_eq!(info.index_histogram.len(), 3);
+        assert_eq!(info.index_histogram.iter().sum::<u64>(), info.pixel_count());
+    }
+}
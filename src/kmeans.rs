@@ -1,10 +1,16 @@
-use crate::{Distance, Zero};
+use crate::{
+    nearest::{Nearest, NearestSearch},
+    par, Distance, Zero,
+};
 use std::iter::Sum;
-use std::ops::{AddAssign, Div, Index};
+use std::ops::{AddAssign, Div, Index, Mul};
 
 pub trait Point<T>:
     Copy
+    + Send
+    + Sync
     + Distance<Output = f32>
+    + Nearest
     + Index<usize, Output = f32>
     + AddAssign
     + Div<f32, Output = T>
@@ -14,7 +20,10 @@ pub trait Point<T>:
 }
 impl<T> Point<T> for T where
     T: Copy
+        + Send
+        + Sync
         + Distance<Output = f32>
+        + Nearest
         + Index<usize, Output = f32>
         + AddAssign
         + Div<f32, Output = T>
@@ -23,65 +32,91 @@ impl<T> Point<T> for T where
 {
 }
 
-pub fn fit<T>(points: &[T], k: usize, tresh: f32, max_iter: usize) -> Vec<T>
+/// Refines `centroids` with Lloyd's algorithm
+pub fn fit<T>(points: &[T], centroids: Vec<T>, tresh: f32, max_iter: usize) -> Vec<T>
 where
     T: Point<T>,
 {
-    let mut centroids: Vec<T> = naive_sharding(points, k);
+    lloyd(points, |&p| (p, p, 1), centroids, tresh, max_iter)
+}
+
+/// Like [`fit`], for points that each stand for `weight` points, such as the
+/// colors of a histogram
+pub fn fit_weighted<T>(
+    points: &[(T, u32)],
+    centroids: Vec<T>,
+    tresh: f32,
+    max_iter: usize,
+) -> Vec<T>
+where
+    T: Point<T> + Mul<f32, Output = T>,
+{
+    lloyd(
+        points,
+        |&(p, weight)| (p, p * weight as f32, weight),
+        centroids,
+        tresh,
+        max_iter,
+    )
+}
+
+/// Runs Lloyd's algorithm on `points`. `point` returns the position of a
+/// point, the position multiplied by its weight and the weight
+fn lloyd<P, T, F>(
+    points: &[P],
+    point: F,
+    mut centroids: Vec<T>,
+    tresh: f32,
+    max_iter: usize,
+) -> Vec<T>
+where
+    P: Sync,
+    T: Point<T>,
+    F: Fn(&P) -> (T, T, u32) + Sync + Send,
+{
+    let k = centroids.len();
 
     // Update centroids
-    let mut max_change = 0.0;
-    let mut iters = max_iter;
-    for i in 0..max_iter {
+    for _ in 0..max_iter {
         let old_centroids = centroids.clone();
-        centroids = points
-            .iter()
-            .fold(vec![(0usize, T::zero()); k], |mut acc, p| {
-                let mut min_dist = centroids[0].distance2(p);
-                let mut min_idx = 0;
-                // change to fluent api
-                for (i, c) in centroids[1..].iter().enumerate() {
-                    let dist = c.distance2(p);
-                    if dist < min_dist {
-                        min_dist = dist;
-                        min_idx = i + 1;
-                    }
+        // Sum up the points closest to each centroid, one chunk at a time
+        let search = T::search(&centroids);
+        let sums = par::fold_chunks(
+            points,
+            || vec![(0usize, T::zero()); k],
+            |mut acc, p| {
+                let (p, weighted, weight) = point(p);
+                let min_idx = search.nearest(&p).unwrap();
+                acc[min_idx].0 += weight as usize;
+                acc[min_idx].1 += weighted;
+                acc
+            },
+        );
+        // Combine chunks in order so that the result doesn't depend on the
+        // number of threads
+        centroids = sums
+            .into_iter()
+            .reduce(|mut acc, chunk| {
+                for (a, (count, sum)) in acc.iter_mut().zip(chunk) {
+                    a.0 += count;
+                    a.1 += sum;
                 }
-                acc[min_idx].0 += 1;
-                acc[min_idx].1 += *p;
                 acc
             })
+            .unwrap_or_else(|| vec![(0, T::zero()); k])
             .into_iter()
-            .map(|(count, sum)| sum / count as f32)
+            .zip(&old_centroids)
+            // Keep centroids that lost all their points where they are
+            .map(|((count, sum), &old)| if count == 0 { old } else { sum / count as f32 })
             .collect();
 
-        max_change = (0..k).fold(0f32, |acc, i| {
+        let max_change = (0..k).fold(0f32, |acc, i| {
             acc.max(centroids[i].distance(&old_centroids[i]))
         });
         if max_change <= tresh {
-            iters = i;
             break;
         }
     }
-    println!("Max change was {} after {} iterations", max_change, iters);
 
     centroids
 }
-
-fn naive_sharding<T>(points: &[T], k: usize) -> Vec<T>
-where
-    T: Point<T>,
-{
-    let mut composites: Vec<_> = points
-        .iter()
-        .enumerate()
-        .map(|(i, p)| (i, p[0] + p[1] + p[2]))
-        .collect();
-    composites.sort_unstable_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
-
-    let shard_size = composites.len().div_ceil(k);
-    composites
-        .chunks(shard_size)
-        .map(|shard| shard.iter().map(|&(i, _)| points[i]).sum::<T>() / shard.len() as f32)
-        .collect::<Vec<_>>()
-}
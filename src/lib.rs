@@ -1,7 +1,19 @@
+mod block;
 pub mod color;
 pub mod compress;
+pub mod debug;
 pub mod decompress;
+pub mod export;
+pub mod inspect;
 mod kmeans;
+mod median_cut;
+pub mod metrics;
+mod nearest;
+mod neuquant;
+mod octree;
+pub mod palette;
+mod par;
+mod wu;
 
 use std::fmt::Debug;
 
@@ -21,24 +33,6 @@ where
 
     /// Returns the squared distance
     fn distance2(&self, other: &Self) -> Self::Output;
-
-    /// Returns the index of the nearest point
-    fn nearest(&self, points: &[Self]) -> Option<usize> {
-        if points.is_empty() {
-            return None;
-        }
-
-        let mut nearest = 0;
-        let mut nearest_distance = self.distance2(&points[0]);
-        for (i, point) in points.iter().enumerate().skip(1) {
-            let distance = self.distance2(point);
-            if distance < nearest_distance {
-                nearest = i;
-                nearest_distance = distance;
-            }
-        }
-        Some(nearest)
-    }
 }
 
 trait Zero {
@@ -49,4 +43,109 @@ trait Zero {
 pub enum PaletteMethod {
     Freq,
     KMeans,
+    MedianCut,
+    Octree,
+    Wu,
+    NeuQuant,
+    /// Map to `Options::fixed_palette` instead of building a palette
+    Fixed,
+}
+
+/// How median cut picks the next box to split
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum MedianCutSplit {
+    /// Split the box with the largest total squared error
+    Variance,
+    /// Split the box covering the most pixels
+    Population,
+}
+
+/// Perceptual color space for measuring color differences or building
+/// palettes
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ColorSpace {
+    CieLab,
+    Itp,
+}
+
+/// How tile palettes are stored
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[repr(u8)]
+pub enum TilePalette {
+    /// Every tile stores its own colors
+    Local = 0,
+    /// Tiles pick their colors from a global palette
+    Shared = 1,
+}
+
+impl TryFrom<u8> for TilePalette {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(TilePalette::Local),
+            1 => Ok(TilePalette::Shared),
+            _ => Err(byte),
+        }
+    }
+}
+
+/// Start of every imgcpr file. Files without it are from before the format
+/// was versioned. They hold the width and height, one palette and then
+/// indices of `LEGACY_INDEX_BITS` bits whatever the palette size
+const MAGIC: [u8; 4] = *b"ICPR";
+/// Format version written after `MAGIC`. Version 1 stores palette indices
+/// with `index_bits` bits
+const VERSION: u8 = 1;
+/// Bits per palette index of unversioned files
+const LEGACY_INDEX_BITS: u8 = 4;
+
+/// How the pixel data is laid out after the header
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+enum Mode {
+    /// One palette for the whole image
+    Global = 0,
+    /// A palette per tile
+    Tiled = 1,
+    /// Two endpoint colors per 4x4 block
+    Block = 2,
+    /// One palette for the whole image, identified by its hash and stored
+    /// elsewhere
+    Referenced = 3,
+}
+
+impl TryFrom<u8> for Mode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(Mode::Global),
+            1 => Ok(Mode::Tiled),
+            2 => Ok(Mode::Block),
+            3 => Ok(Mode::Referenced),
+            _ => Err(byte),
+        }
+    }
+}
+
+/// Returns the origin and size of the tiles covering an image, in row-major
+/// order. Tiles on the right and bottom edges may be smaller
+fn tiles(width: u32, height: u32, tile_size: u32) -> impl Iterator<Item = (u32, u32, u32, u32)> {
+    let step = usize::try_from(tile_size).unwrap();
+    (0..height).step_by(step).flat_map(move |y| {
+        (0..width)
+            .step_by(step)
+            .map(move |x| (x, y, tile_size.min(width - x), tile_size.min(height - y)))
+    })
+}
+
+/// Returns the number of bits used to store each palette index
+fn index_bits(palette_size: usize) -> u8 {
+    match palette_size {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
 }
@@ -1,82 +1,1187 @@
-use clap::Parser;
-use imgcpr::{compress, decompress, PaletteMethod};
-use libflate::deflate::{Decoder, Encoder};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::codecs::pnm::{PnmSubtype, SampleEncoding};
+use image::{ImageFormat, ImageOutputFormat, Rgb, RgbImage};
+use image_webp::{ColorType, WebPEncoder};
+use imgcpr::decompress::{Codec, Header};
+use imgcpr::{
+    compress::{self, ColorCounts, DeltaEStatistic, Goal, Layout, Options, QualityTarget, Target},
+    debug,
+    decompress::{self, Indexed},
+    export::Paletted,
+    inspect::{self, Info},
+    metrics,
+    palette::{self, PaletteFormat},
+    ColorSpace, MedianCutSplit, PaletteMethod, TilePalette,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Cursor, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// ΔE at which the debug heat map saturates
+const HEAT_MAP_MAX_DELTA_E: f32 = 20.0;
 
 /// Compress or decompress image files with imgcpr format
 #[derive(Debug, Parser)]
 struct Cli {
-    /// Path to the image file
-    path: PathBuf,
-    /// Flag for compression
-    #[arg(action, short = 'c', long = "compress")]
-    compress: bool,
-    /// Output path
-    #[arg(short = 'o', long = "output")]
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compress an image into an imgcpr file
+    Compress(CompressArgs),
+    /// Decompress an imgcpr file into an image
+    Decompress(DecompressArgs),
+    /// Quantize an image into an indexed-color PNG or GIF, without the imgcpr
+    /// container
+    Quantize(QuantizeArgs),
+    /// Compute the palette of an image and write it for use in other programs
+    Palette(PaletteArgs),
+    /// Show what an imgcpr file contains
+    Inspect(InspectArgs),
+    /// Print quality metrics between two images of the same size
+    Compare(CompareArgs),
+    /// Compress an image with several palette methods and compare the results
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, Args)]
+struct CompressArgs {
+    /// Image files, directories to search recursively, glob patterns such as
+    /// "assets/**/*.png", or - for stdin
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Output path for a single input file, or - for stdout. Defaults to the
+    /// input path with the imgcpr extension, or stdout when reading stdin
+    #[arg(short = 'o', long = "output", conflicts_with = "out_dir")]
     output: Option<PathBuf>,
+    /// Directory to write outputs to, mirroring the directory tree of the
+    /// inputs. Defaults to next to each input
+    #[arg(long = "out-dir")]
+    out_dir: Option<PathBuf>,
+    /// Number of images compressed at once, defaults to the number of CPUs
+    #[arg(short = 'j', long = "jobs",
+        value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: Option<u16>,
+    /// Skip inputs whose output is newer than the input
+    #[arg(action, short = 'u', long = "update")]
+    update: bool,
     /// Palette selection method
     #[arg(value_enum,
         short = 'p',
         long = "palette",
         default_value_t = PaletteMethod::Freq)]
     palette: PaletteMethod,
-    /// Debug mode
+    /// Compute one palette for all inputs, write it to this path and store
+    /// only its hash in the outputs. Decompress them with --palette-file
+    #[arg(long = "shared-palette",
+        conflicts_with_all = ["palette", "palette_file", "update"])]
+    shared_palette: Option<PathBuf>,
+    /// Also write the decompressed image, a ΔE heat map, the palette and the
+    /// palette indices next to the output, and print quality metrics
     #[arg(action, short = 'd', long = "debug")]
     debug: bool,
+    #[command(flatten)]
+    encoder: EncoderArgs,
+}
+
+#[derive(Debug, Args)]
+struct DecompressArgs {
+    /// Path to the imgcpr file, or - for stdin
+    input: PathBuf,
+    /// Output path, or - for stdout. Defaults to the input path with the
+    /// extension of the format, or stdout when reading stdin
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// Image format to write, defaults to the one matching the output
+    /// extension, or PNG
+    #[arg(value_enum, long = "format")]
+    format: Option<OutputFormat>,
+    /// Overwrite the default output path if it exists
+    #[arg(action, short = 'f', long = "force")]
+    force: bool,
+    /// Palette for files compressed with --shared-palette
+    #[arg(long = "palette-file")]
+    palette_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct QuantizeArgs {
+    /// Path to the image file, or - for stdin
+    input: PathBuf,
+    /// Output path, or - for stdout. Defaults to the input path with
+    /// .quantized and the extension of the format, or stdout when reading
+    /// stdin
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// Image format to write, defaults to the one matching the output
+    /// extension, or PNG
+    #[arg(value_enum, long = "format")]
+    format: Option<IndexedFormat>,
+    /// Palette selection method
+    #[arg(value_enum,
+        short = 'p',
+        long = "palette",
+        default_value_t = PaletteMethod::Freq)]
+    palette: PaletteMethod,
+    #[command(flatten)]
+    encoder: EncoderArgs,
+}
+
+/// Image formats with a palette
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum IndexedFormat {
+    Png,
+    Gif,
+}
+
+#[derive(Debug, Args)]
+struct PaletteArgs {
+    /// Path to the image file, or - for stdin
+    input: PathBuf,
+    /// Output path, or - for stdout. Defaults to the input path with
+    /// .palette and the extension of the format, or stdout when reading stdin
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// Palette format to write, defaults to the one matching the output
+    /// extension, or GIMP palette
+    #[arg(value_enum, long = "format")]
+    format: Option<PaletteFormat>,
+    /// Palette selection method
+    #[arg(value_enum,
+        short = 'p',
+        long = "palette",
+        default_value_t = PaletteMethod::Freq)]
+    palette: PaletteMethod,
+    #[command(flatten)]
+    encoder: EncoderArgs,
+}
+
+#[derive(Debug, Args)]
+struct InspectArgs {
+    /// Path to the imgcpr file, or - for stdin
+    input: PathBuf,
+    /// Print JSON instead of text
+    #[arg(action, long = "json")]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct CompareArgs {
+    /// Path to the original image
+    original: PathBuf,
+    /// Path to the image to compare with, either an image or an imgcpr file
+    decoded: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct BenchArgs {
+    /// Path to the image file
+    input: PathBuf,
+    /// Palette methods to compare, all by default
+    #[arg(value_enum, short = 'p', long = "palette", value_delimiter = ',')]
+    palettes: Vec<PaletteMethod>,
+    #[command(flatten)]
+    encoder: EncoderArgs,
+}
+
+/// Image formats decompressed images can be written in
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Indexed-color PNG, or RGB when there are more than 256 colors
+    Png,
+    Bmp,
+    Tga,
+    /// Binary PPM
+    Ppm,
+    Qoi,
+    WebpLossless,
+    /// Indexed-color GIF, only for images with at most 256 colors
+    Gif,
+}
+
+impl OutputFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match ImageFormat::from_path(path).ok()? {
+            ImageFormat::Png => Some(OutputFormat::Png),
+            ImageFormat::Bmp => Some(OutputFormat::Bmp),
+            ImageFormat::Tga => Some(OutputFormat::Tga),
+            ImageFormat::Pnm => Some(OutputFormat::Ppm),
+            ImageFormat::Qoi => Some(OutputFormat::Qoi),
+            ImageFormat::WebP => Some(OutputFormat::WebpLossless),
+            ImageFormat::Gif => Some(OutputFormat::Gif),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Tga => "tga",
+            OutputFormat::Ppm => "ppm",
+            OutputFormat::Qoi => "qoi",
+            OutputFormat::WebpLossless => "webp",
+            OutputFormat::Gif => "gif",
+        }
+    }
+}
+
+/// Parses a finite number of at least 0
+fn non_negative(arg: &str) -> std::result::Result<f32, String> {
+    let value: f32 = arg.parse().map_err(|err| format!("{}", err))?;
+    if value.is_finite() && value >= 0.0 {
+        Ok(value)
+    } else {
+        Err("must be a finite number of at least 0".into())
+    }
+}
+
+/// Parses a number from 0 to 1
+fn unit_interval(arg: &str) -> std::result::Result<f32, String> {
+    let value: f32 = arg.parse().map_err(|err| format!("{}", err))?;
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err("must be from 0 to 1".into())
+    }
+}
+
+/// Options shared by every command that compresses
+#[derive(Debug, Args)]
+struct EncoderArgs {
+    /// Maximum number of colors in the palette
+    #[arg(short = 'n',
+        long = "colors",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u16).range(1..=256))]
+    colors: u16,
+    /// How median cut picks the next box to split
+    #[arg(value_enum,
+        long = "split",
+        default_value_t = MedianCutSplit::Variance)]
+    split: MedianCutSplit,
+    /// Color space median cut splits the colors in
+    #[arg(value_enum,
+        long = "median-cut-space",
+        default_value_t = ColorSpace::CieLab)]
+    median_cut_space: ColorSpace,
+    /// Bits per channel of the nearest-color lookup cache (faster, but
+    /// approximate below 8)
+    #[arg(long = "lookup-cache",
+        value_parser = clap::value_parser!(u8).range(1..=8))]
+    lookup_cache: Option<u8>,
+    /// NeuQuant samples every nth pixel, from 1 (best quality) to 30 (fastest)
+    #[arg(long = "sample-factor",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u8).range(1..=30))]
+    sample_factor: u8,
+    /// Bits per channel used to group colors for the frequency palette
+    #[arg(long = "freq-bits",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u8).range(1..=8))]
+    freq_bits: u8,
+    /// Minimum redmean distance between frequency palette colors
+    #[arg(long = "freq-spacing",
+        default_value_t = 32.0,
+        value_parser = non_negative)]
+    freq_spacing: f32,
+    /// Refine the palette from the pixels mapped to each color
+    #[arg(action, long = "refine")]
+    refine: bool,
+    /// Give every tile of this many pixels square its own palette
+    #[arg(long = "tile-size",
+        value_parser = clap::value_parser!(u16).range(1..))]
+    tile_size: Option<u16>,
+    /// How tile palettes are stored
+    #[arg(value_enum,
+        long = "tile-palette",
+        default_value_t = TilePalette::Local)]
+    tile_palette: TilePalette,
+    /// Store 4x4 blocks as two endpoint colors and this many bits per pixel
+    /// (2 gives BC1 blocks)
+    #[arg(long = "blocks",
+        conflicts_with = "tile_size",
+        value_parser = clap::value_parser!(u8).range(1..=2))]
+    blocks: Option<u8>,
+    /// Quantize images with at most 256 unique colors instead of storing
+    /// them exactly
+    #[arg(action, long = "no-lossless")]
+    no_lossless: bool,
+    /// Strength of Floyd-Steinberg dithering, from 0 (off) to 1
+    #[arg(long = "dither", default_value_t = 0.0, value_parser = unit_interval)]
+    dither: f32,
+    /// Largest allowed file size in bytes. Palette size, dithering and tiling
+    /// are picked automatically
+    #[arg(long = "target-size", conflicts_with = "target_bpp")]
+    target_size: Option<usize>,
+    /// Largest allowed file size in bits per pixel. Palette size, dithering
+    /// and tiling are picked automatically
+    #[arg(long = "target-bpp", conflicts_with = "max_delta_e")]
+    target_bpp: Option<f32>,
+    /// Largest allowed color difference. The palette is grown until it is
+    /// met
+    #[arg(long = "max-delta-e", conflicts_with = "target_size")]
+    max_delta_e: Option<f32>,
+    /// How per-pixel color differences are summarized for --max-delta-e
+    #[arg(value_enum,
+        long = "delta-e-statistic",
+        default_value_t = DeltaEStatistic::Mean)]
+    delta_e_statistic: DeltaEStatistic,
+    /// Color space color differences are measured in
+    #[arg(value_enum,
+        long = "delta-e-space",
+        default_value_t = ColorSpace::CieLab)]
+    delta_e_space: ColorSpace,
+    /// Palette for --palette fixed: an indexed PNG or GIF, a GIMP .gpl, an
+    /// Adobe .act or a list of hex colors
+    #[arg(long = "palette-file")]
+    palette_file: Option<PathBuf>,
+}
+
+impl EncoderArgs {
+    fn options(&self, palette_method: PaletteMethod) -> Result<Options> {
+        let fixed_palette = match (&palette_method, &self.palette_file) {
+            (PaletteMethod::Fixed, Some(path)) => read_palette(path)?,
+            (PaletteMethod::Fixed, None) => {
+                return Err("--palette fixed needs --palette-file".into())
+            }
+            _ => Vec::new(),
+        };
+        Ok(Options {
+            palette_method,
+            palette_size: self.colors,
+            lookup_cache: self.lookup_cache,
+            median_cut_split: self.split,
+            median_cut_space: self.median_cut_space,
+            neuquant_sample_factor: self.sample_factor,
+            freq_bin_bits: self.freq_bits,
+            freq_min_spacing: self.freq_spacing,
+            refine: self.refine,
+            layout: match (self.tile_size, self.blocks) {
+                (Some(tile_size), _) => Layout::Tiles(tile_size, self.tile_palette),
+                (_, Some(bits)) => Layout::Blocks(bits),
+                (None, None) => Layout::Global,
+            },
+            lossless: !self.no_lossless,
+            dither: self.dither,
+            goal: match (self.target_size, self.target_bpp, self.max_delta_e) {
+                (Some(bytes), _, _) => Some(Goal::Size(Target::Bytes(bytes))),
+                (_, Some(bpp), _) => Some(Goal::Size(Target::BitsPerPixel(bpp))),
+                (_, _, Some(max_delta_e)) => Some(Goal::Quality(QualityTarget {
+                    max_delta_e,
+                    statistic: self.delta_e_statistic,
+                    space: self.delta_e_space,
+                })),
+                (None, None, None) => None,
+            },
+            fixed_palette,
+            reference_palette: false,
+        })
+    }
+
+    /// Fails if options that need more than one palette are given
+    fn check_single_palette(&self) -> Result<()> {
+        if self.tile_size.is_some()
+            || self.blocks.is_some()
+            || self.target_size.is_some()
+            || self.target_bpp.is_some()
+            || self.max_delta_e.is_some()
+        {
+            return Err("tiles, blocks and size or quality targets need the imgcpr format".into());
+        }
+        Ok(())
+    }
+
+    /// Fails if a palette file is given but wouldn't be used
+    fn check_palette_file(&self, palette_method: &PaletteMethod) -> Result<()> {
+        match (palette_method, &self.palette_file) {
+            (PaletteMethod::Fixed, _) | (_, None) => Ok(()),
+            (_, Some(_)) => Err("--palette-file needs --palette fixed".into()),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Compress(args) => run_compress(args),
+        Command::Decompress(args) => run_decompress(args),
+        Command::Quantize(args) => run_quantize(args),
+        Command::Palette(args) => run_palette(args),
+        Command::Inspect(args) => run_inspect(args),
+        Command::Compare(args) => run_compare(args),
+        Command::Bench(args) => run_bench(args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// An image to compress
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+enum Outcome {
+    Compressed {
+        input_size: u64,
+        output_size: u64,
+        /// Messages to show for the file
+        notes: String,
+    },
+    Skipped,
+    Failed(String),
+}
+
+fn run_compress(args: CompressArgs) -> Result<()> {
+    let jobs = collect_jobs(&args)?;
+    if args.debug && jobs.iter().any(|job| is_stdio(&job.output)) {
+        return Err("--debug needs an output file".into());
+    }
+    args.encoder.check_palette_file(&args.palette)?;
+    let mut options = args.encoder.options(args.palette.clone())?;
+    if let Some(path) = &args.shared_palette {
+        args.encoder.check_single_palette()?;
+        options = Options {
+            palette_method: PaletteMethod::Fixed,
+            fixed_palette: write_shared_palette(&jobs, &options, path)?,
+            reference_palette: true,
+            ..options
+        };
+    }
+    let workers = match args.jobs {
+        Some(jobs) => usize::from(jobs),
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    let outcomes = run_parallel(&jobs, workers, |job| {
+        if args.update && is_up_to_date(job) {
+            return Outcome::Skipped;
+        }
+        match compress_file(job, &options, args.debug) {
+            Ok(outcome) => outcome,
+            Err(err) => Outcome::Failed(err.to_string()),
+        }
+    });
+
+    if let [Outcome::Failed(err)] = &outcomes[..] {
+        return Err(err.clone().into());
+    }
+    if let [Outcome::Compressed { notes, .. }] = &outcomes[..] {
+        // Keep stdout clean for the compressed file
+        if is_stdio(&jobs[0].output) {
+            eprint!("{}", notes);
+        } else {
+            print!("{}", notes);
+        }
+        return Ok(());
+    }
+    for (job, outcome) in jobs.iter().zip(&outcomes) {
+        match outcome {
+            // Too noisy for large batches unless asked for
+            Outcome::Compressed { notes, .. } if args.debug => {
+                println!("==> {} <==\n{}", job.input.display(), notes);
+            }
+            Outcome::Failed(err) => eprintln!("error: {}", err),
+            _ => {}
+        }
+    }
+    print_summary(&jobs, &outcomes);
+
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome, Outcome::Failed(_)))
+        .count();
+    if failed > 0 {
+        return Err(format!("{} of {} images failed", failed, jobs.len()).into());
+    }
+    Ok(())
 }
 
-// TODO: try png- or qoi-like compression on index data
-// Deflate performs best, at 122.1 KB for bright-colors
-fn main() {
-    let args = Cli::parse();
+/// Expands the inputs into files and picks their output paths
+fn collect_jobs(args: &CompressArgs) -> Result<Vec<Job>> {
+    if let Some(input) = args.inputs.iter().find(|input| is_stdio(input)) {
+        if args.inputs.len() > 1 || args.out_dir.is_some() {
+            return Err("stdin must be the only input, without --out-dir".into());
+        }
+        return Ok(vec![Job {
+            input: input.clone(),
+            output: args.output.clone().unwrap_or_else(|| input.clone()),
+        }]);
+    }
+
+    // Pairs of an input file and its path relative to the output directory
+    let mut files: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for input in &args.inputs {
+        let pattern = input.to_string_lossy();
+        if !input.exists() && pattern.contains(['*', '?', '[']) {
+            let base = glob_base(input);
+            let paths = glob::glob(&pattern)
+                .map_err(|err| format!("invalid pattern {}: {}", pattern, err))?;
+            let mut found = false;
+            for path in paths {
+                let path = path?;
+                if path.is_file() && is_image(&path) {
+                    let relative = path.strip_prefix(&base).unwrap_or(&path).to_path_buf();
+                    files.push((path, relative));
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(format!("no images match {}", pattern).into());
+            }
+        } else if input.is_dir() {
+            let mut found = Vec::new();
+            find_images(input, &mut found)
+                .map_err(|err| format!("cannot read {}: {}", input.display(), err))?;
+            found.sort();
+            for path in found {
+                let relative = path.strip_prefix(input).unwrap().to_path_buf();
+                files.push((path, relative));
+            }
+        } else {
+            let name = input
+                .file_name()
+                .ok_or_else(|| format!("{} is not a file", input.display()))?;
+            files.push((input.clone(), PathBuf::from(name)));
+        }
+    }
+
+    if let Some(output) = &args.output {
+        let [(input, _)] = &files[..] else {
+            return Err("--output needs a single input file, use --out-dir instead".into());
+        };
+        return Ok(vec![Job {
+            input: input.clone(),
+            output: output.clone(),
+        }]);
+    }
+    let jobs: Vec<Job> = files
+        .into_iter()
+        .map(|(input, relative)| {
+            let output = match &args.out_dir {
+                Some(dir) => dir.join(relative).with_extension("imgcpr"),
+                None => input.with_extension("imgcpr"),
+            };
+            Job { input, output }
+        })
+        .collect();
+
+    // Inputs like a/x.png and b/x.png with --out-dir, or x.png and x.jpg,
+    // would overwrite each other's output
+    let mut outputs: HashMap<&Path, &Path> = HashMap::new();
+    for job in &jobs {
+        if let Some(other) = outputs.insert(&job.output, &job.input) {
+            return Err(format!(
+                "{} and {} would both be written to {}",
+                other.display(),
+                job.input.display(),
+                job.output.display()
+            )
+            .into());
+        }
+    }
+    Ok(jobs)
+}
+
+/// Returns the leading components of a glob pattern without wildcards
+fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '['])
+        })
+        .collect()
+}
+
+fn is_image(path: &Path) -> bool {
+    image::ImageFormat::from_path(path).is_ok()
+}
+
+/// Adds every image below `dir` to `images`
+fn find_images(dir: &Path, images: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_images(&path, images)?;
+        } else if is_image(&path) {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_up_to_date(job: &Job) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified());
+    match (modified(&job.input), modified(&job.output)) {
+        (Ok(input), Ok(output)) => output >= input,
+        _ => false,
+    }
+}
+
+/// Calls `f` on every item from a pool of `workers` threads, returning the
+/// results in order
+fn run_parallel<T, R, F>(items: &[T], workers: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break results;
+                        };
+                        results.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            // Let a panic in a worker surface as it is
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err))
+            })
+            .collect()
+    });
+    results.sort_unstable_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Computes a palette for every input and writes it to `path`, returning its
+/// colors in the order outputs refer to them
+fn write_shared_palette(jobs: &[Job], options: &Options, path: &Path) -> Result<Vec<Rgb<u8>>> {
+    if jobs.iter().any(|job| is_stdio(&job.input)) || is_stdio(path) {
+        return Err("--shared-palette needs input files and a palette file".into());
+    }
+    // Only one image is decoded at a time
+    let mut counts = ColorCounts::new();
+    for job in jobs {
+        counts.add(&open_image(&job.input)?);
+    }
+    let swatches = palette::extract_shared(&counts, options)?;
+    if swatches.is_empty() {
+        return Err("inputs have no pixels".into());
+    }
+
+    let format = PaletteFormat::from_path(path);
+    let title = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut bytes = Vec::new();
+    palette::write(&swatches, format, &title, &mut bytes)?;
+    // Outputs store the palette hash, so it must read back exactly
+    let colors: Vec<Rgb<u8>> = swatches.iter().map(|swatch| swatch.color).collect();
+    if palette::read(&bytes, format).ok().as_ref() != Some(&colors) {
+        return Err(format!(
+            "{:?} palettes can't be read back exactly, use another extension for {}",
+            format,
+            path.display()
+        )
+        .into());
+    }
+    write_file(path, &bytes)?;
+    Ok(colors)
+}
+
+fn compress_file(job: &Job, options: &Options, debug: bool) -> Result<Outcome> {
+    let Job { input, output } = job;
+    let bytes = read_file(input)?;
+    let input_size = bytes.len() as u64;
+    let img = decode_image(input, &bytes)?;
+    let compressed = compress::compress(&img, options)?;
+    let mut notes = String::new();
+    if compressed.lossless {
+        notes.push_str("Stored losslessly\n");
+    } else if let Some(delta_e) = compressed.delta_e {
+        notes += &format!(
+            "Palette size {}, dithering {}: mean ΔE {:.2}, 95th percentile {:.2}\n",
+            compressed.palette_size, compressed.dither, delta_e.mean, delta_e.p95
+        );
+    }
+    if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| format!("cannot create {}: {}", dir.display(), err))?;
+    }
+    write_file(output, &compressed.bytes)?;
+
+    if debug {
+        let indexed =
+            decompress::decompress_with_palette(&compressed.bytes, &options.fixed_palette)?;
+        notes += &metrics::compare(&img, &indexed.image).to_string();
+
+        let with_suffix = |suffix: &str| {
+            let name = output.file_stem().unwrap_or_default().to_string_lossy();
+            output.with_file_name(format!("{}.{}.png", name, suffix))
+        };
+        save_image(&indexed.image, &with_suffix("debug"))?;
+        save_image(
+            &debug::heat_map(&img, &indexed.image, HEAT_MAP_MAX_DELTA_E),
+            &with_suffix("debug.heat"),
+        )?;
+        save_image(
+            &debug::palette_swatch(&indexed.palettes),
+            &with_suffix("debug.palette"),
+        )?;
+        save_image(&debug::index_map(&indexed), &with_suffix("debug.indices"))?;
+    }
+
+    Ok(Outcome::Compressed {
+        input_size,
+        output_size: compressed.bytes.len() as u64,
+        notes,
+    })
+}
+
+/// Prints the sizes of every file and the totals
+fn print_summary(jobs: &[Job], outcomes: &[Outcome]) {
+    let names: Vec<String> = jobs
+        .iter()
+        .map(|job| job.input.display().to_string())
+        .collect();
+    let width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    let ratio = |input: u64, output: u64| input as f64 / output.max(1) as f64;
+
+    println!(
+        "{:<width$} {:>12} {:>12} {:>8}",
+        "input", "original", "compressed", "ratio"
+    );
+    let (mut total_input, mut total_output) = (0, 0);
+    let (mut skipped, mut failed) = (0, 0);
+    for (name, outcome) in names.iter().zip(outcomes) {
+        match *outcome {
+            Outcome::Compressed {
+                input_size,
+                output_size,
+                ..
+            } => {
+                total_input += input_size;
+                total_output += output_size;
+                println!(
+                    "{:<width$} {:>12} {:>12} {:>7.2}x",
+                    name,
+                    input_size,
+                    output_size,
+                    ratio(input_size, output_size)
+                );
+            }
+            Outcome::Skipped => {
+                skipped += 1;
+                println!("{:<width$} {:>12}", name, "up to date");
+            }
+            Outcome::Failed(_) => {
+                failed += 1;
+                println!("{:<width$} {:>12}", name, "failed");
+            }
+        }
+    }
+    println!(
+        "{:<width$} {:>12} {:>12} {:>7.2}x",
+        "total",
+        total_input,
+        total_output,
+        ratio(total_input, total_output)
+    );
+    println!(
+        "{} compressed, {} up to date, {} failed",
+        jobs.len() - skipped - failed,
+        skipped,
+        failed
+    );
+}
+
+fn run_decompress(args: DecompressArgs) -> Result<()> {
+    let output = match args.output {
+        Some(output) => output,
+        None if is_stdio(&args.input) => args.input.clone(),
+        None => {
+            // Usually the original image, don't overwrite it by accident
+            let extension = args.format.unwrap_or(OutputFormat::Png).extension();
+            let output = args.input.with_extension(extension);
+            if output.exists() && !args.force {
+                return Err(format!(
+                    "{} already exists, use --output or --force",
+                    output.display()
+                )
+                .into());
+            }
+            output
+        }
+    };
+    let format = match args.format {
+        Some(format) => format,
+        None if is_stdio(&output) => OutputFormat::Png,
+        None => OutputFormat::from_path(&output).ok_or_else(|| {
+            format!(
+                "cannot tell the image format of {}, use --format",
+                output.display()
+            )
+        })?,
+    };
+
+    let bytes = read_file(&args.input)?;
+    let indexed = match &args.palette_file {
+        Some(path) => decompress::decompress_with_palette(&bytes, &read_palette(path)?),
+        None => decompress::decompress_indexed(&bytes),
+    }
+    .map_err(|err| format!("cannot decompress {}: {}", name(&args.input, "stdin"), err))?;
+    let encoded = encode_image(&indexed, format)
+        .map_err(|err| format!("cannot write {}: {}", name(&output, "stdout"), err))?;
+    write_file(&output, &encoded)
+}
+
+/// Encodes a decoded image, writing PNG and GIF files with its palette
+fn encode_image(indexed: &Indexed, format: OutputFormat) -> Result<Vec<u8>> {
+    let img = &indexed.image;
+    let mut bytes = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Png | OutputFormat::Gif => match Paletted::from_indexed(indexed) {
+            Some(paletted) if format == OutputFormat::Png => paletted.write_png(&mut bytes)?,
+            Some(paletted) => paletted.write_gif(&mut bytes)?,
+            None if format == OutputFormat::Png => {
+                img.write_to(&mut bytes, ImageOutputFormat::Png)?
+            }
+            None => return Err("GIF allows at most 256 colors".into()),
+        },
+        OutputFormat::Bmp => img.write_to(&mut bytes, ImageOutputFormat::Bmp)?,
+        OutputFormat::Tga => img.write_to(&mut bytes, ImageOutputFormat::Tga)?,
+        OutputFormat::Ppm => img.write_to(
+            &mut bytes,
+            ImageOutputFormat::Pnm(PnmSubtype::Pixmap(SampleEncoding::Binary)),
+        )?,
+        OutputFormat::Qoi => img.write_to(&mut bytes, ImageOutputFormat::Qoi)?,
+        OutputFormat::WebpLossless => WebPEncoder::new(&mut bytes).encode(
+            img.as_raw(),
+            img.width(),
+            img.height(),
+            ColorType::Rgb8,
+        )?,
+    }
+    Ok(bytes.into_inner())
+}
+
+fn run_quantize(args: QuantizeArgs) -> Result<()> {
+    let encoder = &args.encoder;
+    encoder.check_single_palette()?;
+
+    let format = match (args.format, &args.output) {
+        (Some(format), _) => format,
+        (None, Some(output)) => match OutputFormat::from_path(output) {
+            Some(OutputFormat::Gif) => IndexedFormat::Gif,
+            _ => IndexedFormat::Png,
+        },
+        (None, None) => IndexedFormat::Png,
+    };
+    let output = args.output.clone().unwrap_or_else(|| {
+        if is_stdio(&args.input) {
+            return args.input.clone();
+        }
+        let extension = match format {
+            IndexedFormat::Png => "quantized.png",
+            IndexedFormat::Gif => "quantized.gif",
+        };
+        args.input.with_extension(extension)
+    });
+
+    let img = open_image(&args.input)?;
+    encoder.check_palette_file(&args.palette)?;
+    let paletted = compress::palettize(&img, &encoder.options(args.palette)?)?;
+    let mut bytes = Vec::new();
+    match format {
+        IndexedFormat::Png => paletted.write_png(&mut bytes)?,
+        IndexedFormat::Gif => paletted.write_gif(&mut bytes)?,
+    }
+    write_file(&output, &bytes)
+}
+
+fn run_palette(args: PaletteArgs) -> Result<()> {
+    let encoder = &args.encoder;
+    encoder.check_single_palette()?;
+    encoder.check_palette_file(&args.palette)?;
+
+    let format = match (args.format, &args.output) {
+        (Some(format), _) => format,
+        (None, Some(output)) => PaletteFormat::from_path(output),
+        (None, None) => PaletteFormat::Gpl,
+    };
+    let output = args.output.clone().unwrap_or_else(|| {
+        if is_stdio(&args.input) {
+            return args.input.clone();
+        }
+        args.input
+            .with_extension(format!("palette.{}", format.extension()))
+    });
+
+    let img = open_image(&args.input)?;
+    let swatches = palette::extract(&img, &encoder.options(args.palette)?)?;
+    let title = match args.input.file_stem() {
+        Some(stem) if !is_stdio(&args.input) => stem.to_string_lossy().into_owned(),
+        _ => "imgcpr".to_owned(),
+    };
+    let mut bytes = Vec::new();
+    palette::write(&swatches, format, &title, &mut bytes)?;
+    write_file(&output, &bytes)
+}
 
-    if args.debug {
-        println!("Args: {:#?}", args);
-        let output = args.output.unwrap_or_else(|| {
-            let mut path = args.path.clone();
-            let name = path.file_stem().unwrap().to_str().unwrap().to_owned();
-            path.set_file_name(name + ".debug.png");
-            path
-        });
+fn run_inspect(args: InspectArgs) -> Result<()> {
+    let bytes = read_file(&args.input)?;
+    let info = inspect::inspect(&bytes)
+        .map_err(|err| format!("cannot inspect {}: {}", args.input.display(), err))?;
+    if args.json {
+        println!("{}", info.to_json());
+    } else {
+        print_info(&info);
+    }
+    Ok(())
+}
 
-        let img = image::open(args.path).unwrap().into_rgb8();
-        let bytes = compress::compress(&img, args.palette);
+/// Width of the longest index histogram bar in characters
+const HISTOGRAM_WIDTH: u64 = 40;
 
-        let img = decompress::decompress(&bytes);
-        println!("Saving image to: {:?}", output);
-        img.save(output).unwrap();
+fn print_info(info: &Info) {
+    let Header {
+        version,
+        width,
+        height,
+        codec,
+    } = info.header;
+    // Only some terminals understand 24-bit colors
+    let truecolor = std::io::stdout().is_terminal()
+        && std::env::var("COLORTERM").is_ok_and(|term| term == "truecolor" || term == "24bit");
+    let swatch = |color: Rgb<u8>| {
+        let [r, g, b] = color.0;
+        if truecolor {
+            format!(
+                "\x1b[48;2;{};{};{}m  \x1b[0m {}",
+                r,
+                g,
+                b,
+                inspect::hex(color)
+            )
+        } else {
+            inspect::hex(color)
+        }
+    };
 
-        return;
+    println!("Dimensions: {}x{}", width, height);
+    match version {
+        Some(version) => println!("Version: {}", version),
+        None => println!("Version: unversioned"),
     }
+    match codec {
+        Codec::Global => println!("Codec: global palette"),
+        Codec::Tiled { tile_size, palette } => println!(
+            "Codec: {}x{} tiles, {} palettes",
+            tile_size,
+            tile_size,
+            palette.to_possible_value().unwrap().get_name()
+        ),
+        Codec::Block { bits } => println!("Codec: 4x4 blocks, {} bits per pixel", bits),
+        Codec::Referenced {
+            palette_hash,
+            palette_size,
+        } => println!(
+            "Codec: referenced palette of {} colors with hash {:016x}",
+            palette_size, palette_hash
+        ),
+    }
+    println!(
+        "Compressed size: {} bytes, {:.3} bits per pixel",
+        info.compressed_size,
+        info.bits_per_pixel()
+    );
+    println!(
+        "Uncompressed size: {} bytes, {} bytes as RGB",
+        info.uncompressed_size,
+        info.raw_size()
+    );
+    println!("Palettes: {}", info.palette_count);
 
-    if args.compress {
-        let output = args.output.unwrap_or_else(|| {
-            let mut path = args.path.clone();
-            path.set_extension("imgcpr");
-            path
-        });
+    println!("Palette: {} colors", info.palette.len());
+    for row in info.palette.chunks(8) {
+        let row: Vec<String> = row.iter().map(|&color| swatch(color)).collect();
+        println!("  {}", row.join("  "));
+    }
 
-        let img = image::open(args.path).unwrap().into_rgb8();
-        let bytes = compress::compress(&img, args.palette);
+    println!("Index usage:");
+    let max = info
+        .index_histogram
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    for (index, &count) in info.index_histogram.iter().enumerate() {
+        let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(max) as usize);
+        let share = 100.0 * count as f64 / info.pixel_count().max(1) as f64;
+        println!("  {:>3} {:>6.2}% {}", index, share, bar);
+    }
+}
 
-        let mut encoder = Encoder::new(Vec::new());
-        encoder.write_all(&bytes).unwrap();
-        let bytes = encoder.finish().into_result().unwrap();
+fn run_compare(args: CompareArgs) -> Result<()> {
+    let original = open_image(&args.original)?;
+    let decoded = match args.decoded.extension() {
+        Some(extension) if extension == "imgcpr" => read_imgcpr(&args.decoded)?,
+        _ => open_image(&args.decoded)?,
+    };
+    if original.dimensions() != decoded.dimensions() {
+        return Err(format!(
+            "images differ in size: {:?} and {:?}",
+            original.dimensions(),
+            decoded.dimensions()
+        )
+        .into());
+    }
+    print!("{}", metrics::compare(&original, &decoded));
+    Ok(())
+}
 
-        std::fs::write(output, bytes).unwrap();
+fn run_bench(args: BenchArgs) -> Result<()> {
+    let img = open_image(&args.input)?;
+    let methods = if args.palettes.is_empty() {
+        // The fixed palette is only compared when one is given
+        PaletteMethod::value_variants()
+            .iter()
+            .filter(|method| {
+                args.encoder.palette_file.is_some() || !matches!(method, PaletteMethod::Fixed)
+            })
+            .cloned()
+            .collect()
     } else {
-        let output = args
-            .output
-            .expect("Output path is required for decompression");
+        args.palettes
+    };
+
+    println!(
+        "{:<10} {:>9} {:>7} {:>9} {:>8} {:>7} {:>7}",
+        "method", "bytes", "bpp", "time ms", "PSNR dB", "SSIM", "mean ΔE"
+    );
+    for method in methods {
+        let name = method.to_possible_value().unwrap().get_name().to_owned();
+        let options = args.encoder.options(method)?;
+        let start = Instant::now();
+        let compressed = compress::compress(&img, &options)?;
+        let elapsed = start.elapsed();
+
+        let decoded = decompress::decompress(&compressed.bytes)?;
+        let report = metrics::compare(&img, &decoded);
+        println!(
+            "{:<10} {:>9} {:>7.3} {:>9} {:>8.2} {:>7.4} {:>7.2}",
+            name,
+            compressed.bytes.len(),
+            bits_per_pixel(compressed.bytes.len(), img.width(), img.height()),
+            elapsed.as_millis(),
+            report.psnr.iter().sum::<f64>() / 3.0,
+            report.ssim,
+            report.delta_e_cielab.mean
+        );
+    }
+    Ok(())
+}
+
+fn bits_per_pixel(bytes: usize, width: u32, height: u32) -> f64 {
+    (bytes * 8) as f64 / (f64::from(width) * f64::from(height)).max(1.0)
+}
+
+/// Whether a path stands for stdin or stdout
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn open_image(path: &Path) -> Result<RgbImage> {
+    decode_image(path, &read_file(path)?)
+}
+
+fn decode_image(path: &Path, bytes: &[u8]) -> Result<RgbImage> {
+    // Like image::open, trust the extension when there is one
+    let mut reader = image::io::Reader::new(Cursor::new(bytes));
+    match ImageFormat::from_path(path) {
+        Ok(format) => reader.set_format(format),
+        Err(_) => reader = reader.with_guessed_format()?,
+    }
+    let img = reader
+        .decode()
+        .map_err(|err| format!("cannot open {}: {}", name(path, "stdin"), err))?;
+    Ok(img.into_rgb8())
+}
 
-        let bytes = std::fs::read(args.path).unwrap();
+/// Saves an image in the format matching the extension of `path`
+fn save_image(img: &RgbImage, path: &Path) -> Result<()> {
+    img.save(path)
+        .map_err(|err| format!("cannot write {}: {}", path.display(), err))?;
+    Ok(())
+}
+
+fn read_palette(path: &Path) -> Result<Vec<Rgb<u8>>> {
+    let bytes = read_file(path)?;
+    palette::read(&bytes, PaletteFormat::from_path(path))
+        .map_err(|err| format!("cannot read palette {}: {}", path.display(), err).into())
+}
 
-        let mut decoder = Decoder::new(&bytes[..]);
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    if is_stdio(path) {
         let mut bytes = Vec::new();
-        _ = decoder.read_to_end(&mut bytes).unwrap();
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut bytes)
+            .map_err(|err| format!("cannot read stdin: {}", err))?;
+        return Ok(bytes);
+    }
+    std::fs::read(path).map_err(|err| format!("cannot read {}: {}", path.display(), err).into())
+}
 
-        let img = decompress::decompress(&bytes);
-        img.save(output).unwrap();
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    if is_stdio(path) {
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(bytes)
+            .and_then(|()| stdout.flush())
+            .map_err(|err| format!("cannot write stdout: {}", err))?;
+        return Ok(());
     }
+    std::fs::write(path, bytes)
+        .map_err(|err| format!("cannot write {}: {}", path.display(), err).into())
+}
+
+/// Names a path in error messages, `stdio` being stdin or stdout
+fn name(path: &Path, stdio: &str) -> String {
+    if is_stdio(path) {
+        stdio.to_owned()
+    } else {
+        path.display().to_string()
+    }
+}
+
+fn read_imgcpr(path: &Path) -> Result<RgbImage> {
+    let bytes = read_file(path)?;
+    let img = decompress::decompress(&bytes)
+        .map_err(|err| format!("cannot decompress {}: {}", name(path, "stdin"), err))?;
+    Ok(img)
 }
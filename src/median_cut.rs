@@ -0,0 +1,109 @@
+use crate::{kmeans::Point, MedianCutSplit};
+use std::ops::{Mul, Range};
+
+/// Heckbert's median cut. Repeatedly splits the box with the highest score
+/// at the weighted median of its channel with the largest variance, and
+/// returns the mean color of every box
+pub fn fit<T>(colors: &[(T, u32)], k: usize, split: MedianCutSplit) -> Vec<T>
+where
+    T: Point<T> + Mul<f32, Output = T>,
+{
+    if colors.is_empty() {
+        return Vec::new();
+    }
+
+    let mut colors = colors.to_vec();
+    let mut boxes = vec![ColorBox::new(&colors, 0..colors.len())];
+    while boxes.len() < k {
+        let Some(i) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.range.len() >= 2)
+            .max_by(|(_, a), (_, b)| a.score(split).total_cmp(&b.score(split)))
+            .map(|(i, _)| i)
+        else {
+            // Every box holds a single color
+            break;
+        };
+
+        let ColorBox {
+            range,
+            weight,
+            axis,
+            ..
+        } = boxes[i].clone();
+        let slice = &mut colors[range.clone()];
+        slice.sort_unstable_by(|a, b| a.0[axis].total_cmp(&b.0[axis]));
+
+        // Split at the weighted median, leaving at least one color per side
+        let mut acc = 0;
+        let mid = slice
+            .iter()
+            .position(|&(_, w)| {
+                acc += u64::from(w);
+                acc * 2 >= weight
+            })
+            .map_or(1, |j| j + 1)
+            .clamp(1, slice.len() - 1);
+
+        let mid = range.start + mid;
+        boxes[i] = ColorBox::new(&colors, range.start..mid);
+        boxes.push(ColorBox::new(&colors, mid..range.end));
+    }
+
+    boxes.into_iter().map(|b| b.mean).collect()
+}
+
+#[derive(Clone)]
+struct ColorBox<T> {
+    range: Range<usize>,
+    weight: u64,
+    mean: T,
+    /// Sum of squared distances to the mean, weighted by population
+    variance: f64,
+    /// Channel with the largest variance
+    axis: usize,
+}
+
+impl<T> ColorBox<T>
+where
+    T: Point<T> + Mul<f32, Output = T>,
+{
+    fn new(colors: &[(T, u32)], range: Range<usize>) -> Self {
+        let colors = &colors[range.clone()];
+        let weight: u64 = colors.iter().map(|&(_, w)| u64::from(w)).sum();
+        let mut sum = T::zero();
+        for &(color, w) in colors {
+            sum += color * w as f32;
+        }
+        let mean = sum / weight as f32;
+
+        let variances = [0, 1, 2].map(|axis| {
+            colors
+                .iter()
+                .map(|&(color, w)| {
+                    let d = f64::from(color[axis] - mean[axis]);
+                    f64::from(w) * d * d
+                })
+                .sum::<f64>()
+        });
+        let axis = (0..3)
+            .max_by(|&a, &b| variances[a].total_cmp(&variances[b]))
+            .unwrap();
+
+        ColorBox {
+            range,
+            weight,
+            mean,
+            variance: variances.iter().sum(),
+            axis,
+        }
+    }
+
+    fn score(&self, split: MedianCutSplit) -> f64 {
+        match split {
+            MedianCutSplit::Variance => self.variance,
+            MedianCutSplit::Population => self.weight as f64,
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! Quality metrics comparing an original image with a decompressed one. Both
+//! images must have the same size.
+
+use crate::{
+    color::{CieLab, Itp, RgbU8},
+    par, ColorSpace, Distance, Image,
+};
+use image::Rgb;
+use std::fmt;
+
+/// Every metric at once
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Peak signal-to-noise ratio of the red, green and blue channels in dB
+    pub psnr: [f64; 3],
+    pub ssim: f64,
+    pub ms_ssim: f64,
+    pub delta_e_cielab: DeltaE,
+    pub delta_e_itp: DeltaE,
+}
+
+pub fn compare(original: &Image, decoded: &Image) -> Report {
+    Report {
+        psnr: psnr(original, decoded),
+        ssim: ssim(original, decoded),
+        ms_ssim: ms_ssim(original, decoded),
+        delta_e_cielab: DeltaE::new(original, decoded, ColorSpace::CieLab),
+        delta_e_itp: DeltaE::new(original, decoded, ColorSpace::Itp),
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b] = self.psnr;
+        writeln!(f, "PSNR R/G/B: {:.2} / {:.2} / {:.2} dB", r, g, b)?;
+        writeln!(f, "SSIM: {:.4}", self.ssim)?;
+        writeln!(f, "MS-SSIM: {:.4}", self.ms_ssim)?;
+        for delta_e in [&self.delta_e_cielab, &self.delta_e_itp] {
+            let name = match delta_e.space {
+                ColorSpace::CieLab => "CIELAB",
+                ColorSpace::Itp => "ITP",
+            };
+            writeln!(
+                f,
+                "\u{394}E {}: mean {:.2}, 95th percentile {:.2}, max {:.2}",
+                name, delta_e.mean, delta_e.p95, delta_e.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn assert_same_size(a: &Image, b: &Image) {
+    assert_eq!(a.dimensions(), b.dimensions(), "images differ in size");
+}
+
+/// Mean squared error over every channel
+pub fn mse(a: &Image, b: &Image) -> f64 {
+    assert_same_size(a, b);
+    let sum: f64 = a
+        .iter()
+        .zip(b.iter())
+        .map(|(&x, &y)| (f64::from(x) - f64::from(y)).powi(2))
+        .sum();
+    sum / a.len().max(1) as f64
+}
+
+/// Peak signal-to-noise ratio of each channel in dB. Identical channels give
+/// infinity
+pub fn psnr(a: &Image, b: &Image) -> [f64; 3] {
+    assert_same_size(a, b);
+    let mut sums = [0f64; 3];
+    for (x, y) in a.pixels().zip(b.pixels()) {
+        for (sum, (&a, &b)) in sums.iter_mut().zip(x.0.iter().zip(&y.0)) {
+            *sum += (f64::from(a) - f64::from(b)).powi(2);
+        }
+    }
+    let pixels = (a.len() / 3).max(1) as f64;
+    sums.map(|sum| 10.0 * (255f64.powi(2) / (sum / pixels)).log10())
+}
+
+/// Color difference between two images
+#[derive(Debug, Copy, Clone)]
+pub struct DeltaE {
+    pub space: ColorSpace,
+    pub mean: f32,
+    /// 95th percentile
+    pub p95: f32,
+    pub max: f32,
+}
+
+impl DeltaE {
+    /// Measures the per-pixel ΔE. CIELAB uses CIE76 and ITP uses ΔE ITP
+    /// from BT.2124
+    pub fn new(a: &Image, b: &Image, space: ColorSpace) -> Self {
+        assert_same_size(a, b);
+        let pairs: Vec<(RgbU8, RgbU8)> = a
+            .pixels()
+            .zip(b.pixels())
+            .map(|(&a, &b)| (a.into(), b.into()))
+            .collect();
+        let mut distances = match space {
+            ColorSpace::CieLab => par::map(&pairs, |&(a, b)| delta_e::<CieLab>(a, b)),
+            ColorSpace::Itp => par::map(&pairs, |&(a, b)| delta_e::<Itp>(a, b)),
+        };
+        if distances.is_empty() {
+            return DeltaE {
+                space,
+                mean: 0.0,
+                p95: 0.0,
+                max: 0.0,
+            };
+        }
+
+        let mean = distances.iter().map(|&d| f64::from(d)).sum::<f64>() / distances.len() as f64;
+        let max = distances.iter().copied().fold(0.0, f32::max);
+        let rank = (distances.len() as f64 * 0.95).ceil() as usize - 1;
+        let (_, &mut p95, _) = distances.select_nth_unstable_by(rank, f32::total_cmp);
+        DeltaE {
+            space,
+            mean: mean as f32,
+            p95,
+            max,
+        }
+    }
+}
+
+fn delta_e<T>(a: RgbU8, b: RgbU8) -> f32
+where
+    T: Distance<Output = f32> + From<Rgb<u8>>,
+{
+    T::from(Rgb(a.0)).distance(&T::from(Rgb(b.0)))
+}
+
+/// Structural similarity of the luma of both images, with an 11x11 Gaussian
+/// window (σ = 1.5)
+pub fn ssim(a: &Image, b: &Image) -> f64 {
+    assert_same_size(a, b);
+    ssim_cs(&Plane::luma(a), &Plane::luma(b)).0
+}
+
+/// Multi-scale SSIM with the weights from Wang et al. (2003). Small images
+/// use fewer scales, with the weights renormalized
+pub fn ms_ssim(a: &Image, b: &Image) -> f64 {
+    const WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+    assert_same_size(a, b);
+
+    let smallest = a.width().min(a.height()).max(1);
+    let scales = (smallest.ilog2() as usize + 1).min(WEIGHTS.len());
+    let total: f64 = WEIGHTS[..scales].iter().sum();
+
+    let (mut x, mut y) = (Plane::luma(a), Plane::luma(b));
+    let mut result = 1.0;
+    for (scale, weight) in WEIGHTS[..scales].iter().enumerate() {
+        let (ssim, cs) = ssim_cs(&x, &y);
+        // Negative values would make fractional powers undefined
+        let value = if scale == scales - 1 { ssim } else { cs };
+        result *= value.max(0.0).powf(weight / total);
+        x = x.downsample();
+        y = y.downsample();
+    }
+    result
+}
+
+/// Returns the mean SSIM and the mean of its contrast-structure term
+fn ssim_cs(x: &Plane, y: &Plane) -> (f64, f64) {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    if x.data.is_empty() {
+        return (1.0, 1.0);
+    }
+
+    let mu_x = x.blur();
+    let mu_y = y.blur();
+    let xx = x.map2(x, |a, b| a * b).blur();
+    let yy = y.map2(y, |a, b| a * b).blur();
+    let xy = x.map2(y, |a, b| a * b).blur();
+
+    let (mut ssim, mut cs) = (0.0, 0.0);
+    for i in 0..x.data.len() {
+        let (mx, my) = (mu_x.data[i], mu_y.data[i]);
+        let var_x = xx.data[i] - mx * mx;
+        let var_y = yy.data[i] - my * my;
+        let cov = xy.data[i] - mx * my;
+        let c = (2.0 * cov + C2) / (var_x + var_y + C2);
+        let l = (2.0 * mx * my + C1) / (mx * mx + my * my + C1);
+        ssim += l * c;
+        cs += c;
+    }
+    let n = x.data.len() as f64;
+    (ssim / n, cs / n)
+}
+
+/// A single channel image
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f64>,
+}
+
+impl Plane {
+    /// BT.601 luma
+    fn luma(img: &Image) -> Self {
+        Plane {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            data: img
+                .pixels()
+                .map(|p| {
+                    let [r, g, b] = p.0.map(f64::from);
+                    0.299 * r + 0.587 * g + 0.114 * b
+                })
+                .collect(),
+        }
+    }
+
+    fn map2(&self, other: &Plane, f: impl Fn(f64, f64) -> f64) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+        }
+    }
+
+    /// Separable Gaussian blur, repeating the edge pixels
+    fn blur(&self) -> Plane {
+        const RADIUS: isize = 5;
+        const SIGMA: f64 = 1.5;
+        let kernel: Vec<f64> = (-RADIUS..=RADIUS)
+            .map(|i| (-((i * i) as f64) / (2.0 * SIGMA * SIGMA)).exp())
+            .collect();
+        let norm: f64 = kernel.iter().sum();
+
+        let (w, h) = (self.width as isize, self.height as isize);
+        let pass = |data: &[f64], dx: isize, dy: isize| -> Vec<f64> {
+            (0..h)
+                .flat_map(|y| (0..w).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    (-RADIUS..=RADIUS)
+                        .zip(&kernel)
+                        .map(|(i, k)| {
+                            let sx = (x + i * dx).clamp(0, w - 1);
+                            let sy = (y + i * dy).clamp(0, h - 1);
+                            k * data[(sy * w + sx) as usize]
+                        })
+                        .sum::<f64>()
+                        / norm
+                })
+                .collect()
+        };
+        let horizontal = pass(&self.data, 1, 0);
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: pass(&horizontal, 0, 1),
+        }
+    }
+
+    /// Halves both dimensions by averaging 2x2 blocks
+    fn downsample(&self) -> Plane {
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let at = |x: usize, y: usize| {
+            self.data[y.min(self.height - 1) * self.width + x.min(self.width - 1)]
+        };
+        let data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                (at(2 * x, 2 * y)
+                    + at(2 * x + 1, 2 * y)
+                    + at(2 * x, 2 * y + 1)
+                    + at(2 * x + 1, 2 * y + 1))
+                    / 4.0
+            })
+            .collect();
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern() -> Image {
+        Image::from_fn(48, 40, |x, y| {
+            Rgb([
+                (40 + x * 3) as u8,
+                (30 + (x * y) % 150) as u8,
+                (200 - y * 4) as u8,
+            ])
+        })
+    }
+
+    #[test]
+    fn identical_images() {
+        let img = pattern();
+        assert_eq!(psnr(&img, &img), [f64::INFINITY; 3]);
+        assert_eq!(mse(&img, &img), 0.0);
+        assert!((ssim(&img, &img) - 1.0).abs() < 1e-9);
+        assert!((ms_ssim(&img, &img) - 1.0).abs() < 1e-9);
+        for space in [ColorSpace::CieLab, ColorSpace::Itp] {
+            assert_eq!(DeltaE::new(&img, &img, space).max, 0.0);
+        }
+    }
+
+    #[test]
+    fn offset_red_channel() {
+        let img = pattern();
+        for offset in [1u8, 4, 10] {
+            let mut shifted = img.clone();
+            for pixel in shifted.pixels_mut() {
+                pixel.0[0] += offset;
+            }
+            let expected = 10.0 * (255f64.powi(2) / f64::from(offset).powi(2)).log10();
+            let [r, g, b] = psnr(&img, &shifted);
+            assert!(
+                (r - expected).abs() < 1e-9,
+                "{} dB, expected {}",
+                r,
+                expected
+            );
+            assert_eq!([g, b], [f64::INFINITY; 2]);
+            assert!((mse(&img, &shifted) - f64::from(offset).powi(2) / 3.0).abs() < 1e-9);
+        }
+    }
+}
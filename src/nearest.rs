@@ -0,0 +1,279 @@
+mod cache;
+mod kdtree;
+mod simd;
+mod vptree;
+
+pub use cache::LookupCache;
+pub use kdtree::KdTree;
+pub use vptree::VpTree;
+
+use crate::{
+    color::{CieLab, Itp, RgbU8},
+    Distance,
+};
+
+/// A palette that has been preprocessed for repeated nearest-color queries
+pub trait NearestSearch<T>: Sync {
+    /// Returns the index of the nearest palette color
+    fn nearest(&self, color: &T) -> Option<usize>;
+}
+
+pub trait Nearest: Distance + Sized {
+    type Search: NearestSearch<Self>;
+
+    /// Preprocesses `palette` for repeated nearest-color queries. Searches
+    /// return the first palette color with the smallest `distance2`
+    fn search(palette: &[Self]) -> Self::Search;
+}
+
+/// Palettes with at least this many colors are searched with a tree instead
+/// of a linear scan, as (scalar, SIMD) thresholds. The SIMD scan compares 4
+/// or 8 colors at once, so the trees only pay off for larger palettes. The
+/// SIMD thresholds must stay at most 256, the largest palette size, or the
+/// tree is never used.
+const KD_TREE_THRESHOLD: (usize, usize) = (48, 192);
+const VP_TREE_THRESHOLD: (usize, usize) = (96, 192);
+
+fn use_tree(len: usize, threshold: (usize, usize)) -> bool {
+    match simd::Kernel::detect() {
+        simd::Kernel::Scalar => len >= threshold.0,
+        _ => len >= threshold.1,
+    }
+}
+
+/// Picks between a linear scan and a tree depending on the palette size
+pub enum Search<L, T> {
+    Linear(L),
+    Tree(T),
+}
+
+impl<C, L, T> NearestSearch<C> for Search<L, T>
+where
+    L: NearestSearch<C>,
+    T: NearestSearch<C>,
+{
+    fn nearest(&self, color: &C) -> Option<usize> {
+        match self {
+            Search::Linear(search) => search.nearest(color),
+            Search::Tree(search) => search.nearest(color),
+        }
+    }
+}
+
+/// Palette stored as one array per channel, so that several palette colors
+/// can be compared at once
+pub struct SoaPalette<C> {
+    channels: [Vec<C>; 3],
+    kernel: simd::Kernel,
+}
+
+impl<C: Copy> SoaPalette<C> {
+    fn channels(&self) -> [&[C]; 3] {
+        [&self.channels[0], &self.channels[1], &self.channels[2]]
+    }
+
+    fn new(palette: impl Iterator<Item = [C; 3]>) -> Self {
+        let mut channels = [Vec::new(), Vec::new(), Vec::new()];
+        for color in palette {
+            for (channel, value) in channels.iter_mut().zip(color) {
+                channel.push(value);
+            }
+        }
+        SoaPalette {
+            channels,
+            kernel: simd::Kernel::detect(),
+        }
+    }
+}
+
+impl NearestSearch<CieLab> for SoaPalette<f32> {
+    fn nearest(&self, color: &CieLab) -> Option<usize> {
+        simd::nearest_euclidean(self.kernel, self.channels(), color.0).map(|(i, _)| i)
+    }
+}
+
+impl Nearest for CieLab {
+    type Search = Search<SoaPalette<f32>, KdTree>;
+
+    fn search(palette: &[Self]) -> Self::Search {
+        let palette = palette.iter().map(|c| c.0);
+        if use_tree(palette.len(), KD_TREE_THRESHOLD) {
+            Search::Tree(KdTree::new(palette))
+        } else {
+            Search::Linear(SoaPalette::new(palette))
+        }
+    }
+}
+
+impl NearestSearch<Itp> for SoaPalette<f32> {
+    fn nearest(&self, color: &Itp) -> Option<usize> {
+        simd::nearest_euclidean(self.kernel, self.channels(), color.0).map(|(i, _)| i)
+    }
+}
+
+impl Nearest for Itp {
+    type Search = Search<SoaPalette<f32>, KdTree>;
+
+    fn search(palette: &[Self]) -> Self::Search {
+        let palette = palette.iter().map(|c| c.0);
+        if use_tree(palette.len(), KD_TREE_THRESHOLD) {
+            Search::Tree(KdTree::new(palette))
+        } else {
+            Search::Linear(SoaPalette::new(palette))
+        }
+    }
+}
+
+impl NearestSearch<RgbU8> for SoaPalette<i32> {
+    fn nearest(&self, color: &RgbU8) -> Option<usize> {
+        simd::nearest_redmean(self.kernel, self.channels(), color.0.map(i32::from)).map(|(i, _)| i)
+    }
+}
+
+impl Nearest for RgbU8 {
+    type Search = Search<SoaPalette<i32>, VpTree<RgbU8>>;
+
+    fn search(palette: &[Self]) -> Self::Search {
+        if use_tree(palette.len(), VP_TREE_THRESHOLD) {
+            Search::Tree(VpTree::new(palette))
+        } else {
+            Search::Linear(SoaPalette::new(palette.iter().map(|c| c.0.map(i32::from))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    /// Deterministic xorshift so the tests don't need a random crate
+    pub(super) struct Rng(pub(super) u32);
+
+    impl Rng {
+        pub(super) fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        pub(super) fn rgb(&mut self) -> [u8; 3] {
+            [0, 8, 16].map(|shift| (self.next() >> shift) as u8)
+        }
+
+        pub(super) fn lab(&mut self) -> [f32; 3] {
+            let mut unit = || (self.next() >> 8) as f32 / (1 << 24) as f32;
+            [
+                unit() * 100.0,
+                unit() * 256.0 - 128.0,
+                unit() * 256.0 - 128.0,
+            ]
+        }
+    }
+
+    /// Plain linear scan with the `Distance` implementations, returning the
+    /// first color with the smallest distance
+    pub(super) fn scan<T: Distance<Output = f32>>(palette: &[T], color: &T) -> (usize, f32) {
+        let mut nearest = (0, color.distance2(&palette[0]));
+        for (i, c) in palette.iter().enumerate().skip(1) {
+            let distance = color.distance2(c);
+            if distance < nearest.1 {
+                nearest = (i, distance);
+            }
+        }
+        nearest
+    }
+
+    /// Random colors, with some of them repeated so that searches hit ties
+    fn palette(rng: &mut Rng, len: usize) -> Vec<Rgb<u8>> {
+        let mut palette: Vec<_> = (0..len).map(|_| Rgb(rng.rgb())).collect();
+        for i in (5..len).step_by(7) {
+            palette[i] = palette[rng.next() as usize % i];
+        }
+        palette
+    }
+
+    /// Checks `search` against a scan of `palette`, both for random colors
+    /// and for the palette colors themselves
+    fn check<T, S>(rng: &mut Rng, palette: &[Rgb<u8>], search: &S)
+    where
+        T: From<Rgb<u8>> + Distance<Output = f32>,
+        S: NearestSearch<T>,
+    {
+        let converted: Vec<T> = palette.iter().map(|&c| c.into()).collect();
+        let queries = (0..64)
+            .map(|_| Rgb(rng.rgb()))
+            .chain(palette.iter().copied());
+        for color in queries {
+            let color = T::from(color);
+            assert_eq!(
+                search.nearest(&color),
+                Some(scan(&converted, &color).0),
+                "{} colors",
+                palette.len()
+            );
+        }
+    }
+
+    /// Sizes around the tree leaf size and the thresholds, up to 256 colors
+    const LENGTHS: [usize; 9] = [1, 16, 17, 48, 96, 100, 191, 192, 256];
+
+    #[test]
+    fn kd_tree_matches_scan() {
+        let mut rng = Rng(0x2545_f491);
+        for len in LENGTHS {
+            let palette = palette(&mut rng, len);
+            let lab = KdTree::new(palette.iter().map(|&c| CieLab::from(c).0));
+            check::<CieLab, _>(&mut rng, &palette, &lab);
+            let itp = KdTree::new(palette.iter().map(|&c| Itp::from(c).0));
+            check::<Itp, _>(&mut rng, &palette, &itp);
+        }
+    }
+
+    #[test]
+    fn vp_tree_matches_scan() {
+        let mut rng = Rng(0x6c07_8965);
+        for len in LENGTHS {
+            let palette = palette(&mut rng, len);
+            let rgb: Vec<RgbU8> = palette.iter().map(|&c| c.into()).collect();
+            check::<RgbU8, _>(&mut rng, &palette, &VpTree::new(&rgb));
+        }
+    }
+
+    #[test]
+    fn search_matches_scan() {
+        let mut rng = Rng(0x1b87_3593);
+        for len in LENGTHS {
+            let palette = palette(&mut rng, len);
+            let lab: Vec<CieLab> = palette.iter().map(|&c| c.into()).collect();
+            check::<CieLab, _>(&mut rng, &palette, &CieLab::search(&lab));
+            let itp: Vec<Itp> = palette.iter().map(|&c| c.into()).collect();
+            check::<Itp, _>(&mut rng, &palette, &Itp::search(&itp));
+            let rgb: Vec<RgbU8> = palette.iter().map(|&c| c.into()).collect();
+            check::<RgbU8, _>(&mut rng, &palette, &RgbU8::search(&rgb));
+        }
+    }
+
+    #[test]
+    fn lookup_cache_matches_scan_of_cell_center() {
+        let mut rng = Rng(0xcc9e_2d51);
+        let palette = palette(&mut rng, 256);
+        let rgb: Vec<RgbU8> = palette.iter().map(|&c| c.into()).collect();
+        let search = RgbU8::search(&rgb);
+        for bits in [1, 3, 5] {
+            let cache = LookupCache::new::<RgbU8, _>(bits, &search);
+            let shift = 8 - bits;
+            for _ in 0..256 {
+                let color = rng.rgb();
+                let center = color.map(|c| (c >> shift) << shift | ((1 << shift) >> 1));
+                assert_eq!(
+                    cache.nearest(&RgbU8(color)),
+                    scan(&rgb, &RgbU8(center)).0,
+                    "{} bits",
+                    bits
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use super::NearestSearch;
+use crate::{color::RgbU8, par};
+use image::Rgb;
+
+/// Table of nearest palette indices for every cell of a regular grid over
+/// RGB. Every color in a cell shares the answer for the cell's center, so
+/// lookups are approximate unless the grid has 8 bits per channel.
+pub struct LookupCache {
+    bits: u8,
+    table: Vec<u16>,
+}
+
+impl LookupCache {
+    /// Builds a cache with `bits` bits per channel, between 1 and 8
+    pub fn new<T, S>(bits: u8, search: &S) -> Self
+    where
+        T: From<Rgb<u8>>,
+        S: NearestSearch<T>,
+    {
+        assert!((1..=8).contains(&bits), "cache bits must be in 1..=8");
+
+        let shift = 8 - bits;
+        let half = (1u8 << shift) >> 1;
+        let mask = (1u32 << bits) - 1;
+        let cells: Vec<u32> = (0..1u32 << (3 * bits)).collect();
+        let table = par::map(&cells, |&cell| {
+            let channel = |i: u8| (((cell >> (i * bits)) & mask) as u8) << shift | half;
+            let center = Rgb([channel(2), channel(1), channel(0)]);
+            let index = search.nearest(&center.into()).unwrap();
+            u16::try_from(index).unwrap()
+        });
+
+        LookupCache { bits, table }
+    }
+
+    pub fn nearest(&self, color: &RgbU8) -> usize {
+        let shift = 8 - self.bits;
+        let key = color.0.iter().fold(0usize, |key, &channel| {
+            (key << self.bits) | usize::from(channel >> shift)
+        });
+        self.table[key].into()
+    }
+}
@@ -0,0 +1,141 @@
+use super::{simd, NearestSearch};
+use crate::color::{CieLab, Itp};
+
+/// Leaves hold up to this many points, which are scanned linearly
+const LEAF_SIZE: usize = 16;
+
+/// k-d tree over 3-D points with the Euclidean distance
+pub struct KdTree {
+    nodes: Vec<Node>,
+    /// Points reordered so that every leaf is a contiguous range, stored as
+    /// one array per channel. Within a leaf, points are sorted by index
+    channels: [Vec<f32>; 3],
+    indices: Vec<u32>,
+    kernel: simd::Kernel,
+}
+
+enum Node {
+    Split {
+        axis: u8,
+        value: f32,
+        /// The left child always directly follows its parent
+        right: u32,
+    },
+    Leaf {
+        start: u32,
+        end: u32,
+    },
+}
+
+impl KdTree {
+    pub fn new(palette: impl Iterator<Item = [f32; 3]>) -> Self {
+        let mut points: Vec<_> = palette
+            .enumerate()
+            .map(|(i, p)| (u32::try_from(i).unwrap(), p))
+            .collect();
+        let mut nodes = Vec::new();
+        if !points.is_empty() {
+            Self::build(&mut points, 0, &mut nodes);
+        }
+        KdTree {
+            nodes,
+            channels: [0, 1, 2].map(|c| points.iter().map(|&(_, p)| p[c]).collect()),
+            indices: points.iter().map(|&(i, _)| i).collect(),
+            kernel: simd::Kernel::detect(),
+        }
+    }
+
+    fn build(points: &mut [(u32, [f32; 3])], start: usize, nodes: &mut Vec<Node>) {
+        if points.len() <= LEAF_SIZE {
+            // Scanning in index order keeps ties going to the lowest index
+            points.sort_unstable_by_key(|&(i, _)| i);
+            nodes.push(Node::Leaf {
+                start: u32::try_from(start).unwrap(),
+                end: u32::try_from(start + points.len()).unwrap(),
+            });
+            return;
+        }
+
+        // Split along the axis with the largest spread
+        let axis = (0..3)
+            .map(|axis| {
+                let (min, max) = points.iter().fold((f32::MAX, f32::MIN), |(min, max), p| {
+                    (min.min(p.1[axis]), max.max(p.1[axis]))
+                });
+                (axis, max - min)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap()
+            .0;
+        let mid = points.len() / 2;
+        points.select_nth_unstable_by(mid, |a, b| a.1[axis].total_cmp(&b.1[axis]));
+
+        let id = nodes.len();
+        nodes.push(Node::Split {
+            axis: axis as u8,
+            value: points[mid].1[axis],
+            right: 0,
+        });
+        let (left, right) = points.split_at_mut(mid);
+        Self::build(left, start, nodes);
+        let right_id = u32::try_from(nodes.len()).unwrap();
+        if let Node::Split { right, .. } = &mut nodes[id] {
+            *right = right_id;
+        }
+        Self::build(right, start + mid, nodes);
+    }
+
+    fn search(&self, node: usize, color: [f32; 3], best: &mut (u32, f32)) {
+        match self.nodes[node] {
+            Node::Leaf { start, end } => {
+                let range = start as usize..end as usize;
+                let channels = [0, 1, 2].map(|c| &self.channels[c][range.clone()]);
+                let (i, distance) = simd::nearest_euclidean(self.kernel, channels, color).unwrap();
+                let index = self.indices[range.start + i];
+                if distance < best.1 || (distance == best.1 && index < best.0) {
+                    *best = (index, distance);
+                }
+            }
+            Node::Split { axis, value, right } => {
+                let diff = color[usize::from(axis)] - value;
+                let (near, far) = if diff < 0.0 {
+                    (node + 1, right as usize)
+                } else {
+                    (right as usize, node + 1)
+                };
+                self.search(near, color, best);
+                // `<=` so that ties with a lower index on the far side are
+                // still found
+                if diff * diff <= best.1 {
+                    self.search(far, color, best);
+                }
+            }
+        }
+    }
+
+    fn nearest3(&self, color: [f32; 3]) -> Option<usize> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut best = (u32::MAX, f32::INFINITY);
+        self.search(0, color, &mut best);
+        // Only happens if every distance is NaN
+        if best.0 == u32::MAX {
+            return Some(0);
+        }
+        Some(best.0 as usize)
+    }
+}
+
+impl NearestSearch<CieLab> for KdTree {
+    fn nearest(&self, color: &CieLab) -> Option<usize> {
+        self.nearest3(color.0)
+    }
+}
+
+impl NearestSearch<Itp> for KdTree {
+    fn nearest(&self, color: &Itp) -> Option<usize> {
+        self.nearest3(color.0)
+    }
+}
@@ -0,0 +1,450 @@
+//! Nearest-color kernels over palettes stored as one array per channel. Every
+//! kernel performs exactly the same floating point operations as the scalar
+//! `Distance` implementations and breaks ties towards the lower index, so the
+//! results always match a plain linear scan.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    /// Returns the fastest kernel supported by the current CPU
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            return Kernel::Avx2;
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Kernel::Neon;
+        }
+        Kernel::Scalar
+    }
+}
+
+/// Same as `CieLab::distance2` and `Itp::distance2`
+#[inline]
+fn euclidean2(channels: [&[f32]; 3], i: usize, color: [f32; 3]) -> f32 {
+    let d0 = color[0] - channels[0][i];
+    let d1 = color[1] - channels[1][i];
+    let d2 = color[2] - channels[2][i];
+    d0 * d0 + d1 * d1 + d2 * d2
+}
+
+/// Same as `RgbU8::distance2`
+#[inline]
+fn redmean2(channels: [&[i32]; 3], i: usize, color: [i32; 3]) -> f32 {
+    let r_mean = (color[0] + channels[0][i]) / 2;
+    let dr = color[0] - channels[0][i];
+    let dg = color[1] - channels[1][i];
+    let db = color[2] - channels[2][i];
+
+    let dr2 = ((512 + r_mean) * dr * dr) as f32 / 256.0;
+    let dg2 = (4 * dg * dg) as f32;
+    let db2 = ((767 - r_mean) * db * db) as f32 / 256.0;
+    (dr2 + dg2 + db2) / 3.0
+}
+
+/// Returns the index of the first nearest color and its squared distance
+pub fn nearest_euclidean(
+    kernel: Kernel,
+    channels: [&[f32]; 3],
+    color: [f32; 3],
+) -> Option<(usize, f32)> {
+    if channels[0].is_empty() {
+        return None;
+    }
+
+    let seed = euclidean2(channels, 0, color);
+    let (mut nearest, mut nearest_distance, done) = match kernel {
+        Kernel::Scalar => (0, seed, 0),
+        // SAFETY: the kernel is only selected if the CPU supports it
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => unsafe { x86::euclidean_avx2(channels, color, seed) },
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon => unsafe { arm::euclidean_neon(channels, color, seed) },
+    };
+    for i in done.max(1)..channels[0].len() {
+        let distance = euclidean2(channels, i, color);
+        if distance < nearest_distance {
+            nearest = i;
+            nearest_distance = distance;
+        }
+    }
+    Some((nearest, nearest_distance))
+}
+
+/// Returns the index of the first nearest color and its squared distance
+pub fn nearest_redmean(
+    kernel: Kernel,
+    channels: [&[i32]; 3],
+    color: [i32; 3],
+) -> Option<(usize, f32)> {
+    if channels[0].is_empty() {
+        return None;
+    }
+
+    let seed = redmean2(channels, 0, color);
+    let (mut nearest, mut nearest_distance, done) = match kernel {
+        Kernel::Scalar => (0, seed, 0),
+        // SAFETY: the kernel is only selected if the CPU supports it
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => unsafe { x86::redmean_avx2(channels, color, seed) },
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon => unsafe { arm::redmean_neon(channels, color, seed) },
+    };
+    for i in done.max(1)..channels[0].len() {
+        let distance = redmean2(channels, i, color);
+        if distance < nearest_distance {
+            nearest = i;
+            nearest_distance = distance;
+        }
+    }
+    Some((nearest, nearest_distance))
+}
+
+/// Combines the per-lane minimums. Each lane only ever saw increasing
+/// indices, so picking the lowest index among equal distances gives the
+/// first minimum overall.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn reduce_lanes<const N: usize>(distances: [f32; N], indices: [u32; N]) -> (usize, f32) {
+    let mut nearest = indices[0];
+    let mut nearest_distance = distances[0];
+    for (&distance, &index) in distances.iter().zip(&indices).skip(1) {
+        if distance < nearest_distance || (distance == nearest_distance && index < nearest) {
+            nearest = index;
+            nearest_distance = distance;
+        }
+    }
+    (nearest as usize, nearest_distance)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::reduce_lanes;
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 8;
+
+    /// Searches all full chunks of 8 palette colors. Returns the nearest
+    /// index, its distance and the number of colors searched. Every lane is
+    /// seeded with the distance to color 0.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn euclidean_avx2(
+        channels: [&[f32]; 3],
+        color: [f32; 3],
+        seed: f32,
+    ) -> (usize, f32, usize) {
+        let len = channels[0].len() / LANES * LANES;
+        let c0 = _mm256_set1_ps(color[0]);
+        let c1 = _mm256_set1_ps(color[1]);
+        let c2 = _mm256_set1_ps(color[2]);
+
+        let mut best_d = _mm256_set1_ps(seed);
+        let mut best_i = _mm256_setzero_si256();
+        let mut idx = _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7);
+        let step = _mm256_set1_epi32(LANES as i32);
+        for i in (0..len).step_by(LANES) {
+            let d0 = _mm256_sub_ps(c0, _mm256_loadu_ps(channels[0].as_ptr().add(i)));
+            let d1 = _mm256_sub_ps(c1, _mm256_loadu_ps(channels[1].as_ptr().add(i)));
+            let d2 = _mm256_sub_ps(c2, _mm256_loadu_ps(channels[2].as_ptr().add(i)));
+            let d = _mm256_add_ps(
+                _mm256_add_ps(_mm256_mul_ps(d0, d0), _mm256_mul_ps(d1, d1)),
+                _mm256_mul_ps(d2, d2),
+            );
+
+            let lt = _mm256_cmp_ps::<_CMP_LT_OQ>(d, best_d);
+            best_d = _mm256_blendv_ps(best_d, d, lt);
+            best_i = _mm256_blendv_epi8(best_i, idx, _mm256_castps_si256(lt));
+            idx = _mm256_add_epi32(idx, step);
+        }
+
+        let (nearest, distance) = reduce(best_d, best_i);
+        (nearest, distance, len)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn redmean_avx2(
+        channels: [&[i32]; 3],
+        color: [i32; 3],
+        seed: f32,
+    ) -> (usize, f32, usize) {
+        let len = channels[0].len() / LANES * LANES;
+        let r = _mm256_set1_epi32(color[0]);
+        let g = _mm256_set1_epi32(color[1]);
+        let b = _mm256_set1_epi32(color[2]);
+        let w512 = _mm256_set1_epi32(512);
+        let w767 = _mm256_set1_epi32(767);
+        let w4 = _mm256_set1_epi32(4);
+        // Multiplying by a power of 2 is exact, so this matches `/ 256.0`
+        let inv256 = _mm256_set1_ps(1.0 / 256.0);
+        let three = _mm256_set1_ps(3.0);
+
+        let mut best_d = _mm256_set1_ps(seed);
+        let mut best_i = _mm256_setzero_si256();
+        let mut idx = _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7);
+        let step = _mm256_set1_epi32(LANES as i32);
+        for i in (0..len).step_by(LANES) {
+            let pr = _mm256_loadu_si256(channels[0].as_ptr().add(i).cast());
+            let pg = _mm256_loadu_si256(channels[1].as_ptr().add(i).cast());
+            let pb = _mm256_loadu_si256(channels[2].as_ptr().add(i).cast());
+
+            let r_mean = _mm256_srai_epi32::<1>(_mm256_add_epi32(r, pr));
+            let dr = _mm256_sub_epi32(r, pr);
+            let dg = _mm256_sub_epi32(g, pg);
+            let db = _mm256_sub_epi32(b, pb);
+
+            let dr2 =
+                _mm256_mullo_epi32(_mm256_mullo_epi32(_mm256_add_epi32(w512, r_mean), dr), dr);
+            let dg2 = _mm256_mullo_epi32(_mm256_mullo_epi32(w4, dg), dg);
+            let db2 =
+                _mm256_mullo_epi32(_mm256_mullo_epi32(_mm256_sub_epi32(w767, r_mean), db), db);
+            let dr2 = _mm256_mul_ps(_mm256_cvtepi32_ps(dr2), inv256);
+            let dg2 = _mm256_cvtepi32_ps(dg2);
+            let db2 = _mm256_mul_ps(_mm256_cvtepi32_ps(db2), inv256);
+            let d = _mm256_div_ps(_mm256_add_ps(_mm256_add_ps(dr2, dg2), db2), three);
+
+            let lt = _mm256_cmp_ps::<_CMP_LT_OQ>(d, best_d);
+            best_d = _mm256_blendv_ps(best_d, d, lt);
+            best_i = _mm256_blendv_epi8(best_i, idx, _mm256_castps_si256(lt));
+            idx = _mm256_add_epi32(idx, step);
+        }
+
+        let (nearest, distance) = reduce(best_d, best_i);
+        (nearest, distance, len)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn reduce(best_d: __m256, best_i: __m256i) -> (usize, f32) {
+        let mut distances = [0f32; LANES];
+        let mut indices = [0u32; LANES];
+        _mm256_storeu_ps(distances.as_mut_ptr(), best_d);
+        _mm256_storeu_si256(indices.as_mut_ptr().cast(), best_i);
+        reduce_lanes(distances, indices)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use super::reduce_lanes;
+    use std::arch::aarch64::*;
+
+    const LANES: usize = 4;
+
+    /// Searches all full chunks of 4 palette colors. Returns the nearest
+    /// index, its distance and the number of colors searched. Every lane is
+    /// seeded with the distance to color 0.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn euclidean_neon(
+        channels: [&[f32]; 3],
+        color: [f32; 3],
+        seed: f32,
+    ) -> (usize, f32, usize) {
+        let len = channels[0].len() / LANES * LANES;
+        let c0 = vdupq_n_f32(color[0]);
+        let c1 = vdupq_n_f32(color[1]);
+        let c2 = vdupq_n_f32(color[2]);
+
+        let mut best_d = vdupq_n_f32(seed);
+        let mut best_i = vdupq_n_u32(0);
+        let mut idx = vld1q_u32([0, 1, 2, 3].as_ptr());
+        let step = vdupq_n_u32(LANES as u32);
+        for i in (0..len).step_by(LANES) {
+            let d0 = vsubq_f32(c0, vld1q_f32(channels[0].as_ptr().add(i)));
+            let d1 = vsubq_f32(c1, vld1q_f32(channels[1].as_ptr().add(i)));
+            let d2 = vsubq_f32(c2, vld1q_f32(channels[2].as_ptr().add(i)));
+            let d = vaddq_f32(
+                vaddq_f32(vmulq_f32(d0, d0), vmulq_f32(d1, d1)),
+                vmulq_f32(d2, d2),
+            );
+
+            let lt = vcltq_f32(d, best_d);
+            best_d = vbslq_f32(lt, d, best_d);
+            best_i = vbslq_u32(lt, idx, best_i);
+            idx = vaddq_u32(idx, step);
+        }
+
+        let (nearest, distance) = reduce(best_d, best_i);
+        (nearest, distance, len)
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn redmean_neon(
+        channels: [&[i32]; 3],
+        color: [i32; 3],
+        seed: f32,
+    ) -> (usize, f32, usize) {
+        let len = channels[0].len() / LANES * LANES;
+        let r = vdupq_n_s32(color[0]);
+        let g = vdupq_n_s32(color[1]);
+        let b = vdupq_n_s32(color[2]);
+        let w512 = vdupq_n_s32(512);
+        let w767 = vdupq_n_s32(767);
+        let w4 = vdupq_n_s32(4);
+        // Multiplying by a power of 2 is exact, so this matches `/ 256.0`
+        let inv256 = vdupq_n_f32(1.0 / 256.0);
+        let three = vdupq_n_f32(3.0);
+
+        let mut best_d = vdupq_n_f32(seed);
+        let mut best_i = vdupq_n_u32(0);
+        let mut idx = vld1q_u32([0, 1, 2, 3].as_ptr());
+        let step = vdupq_n_u32(LANES as u32);
+        for i in (0..len).step_by(LANES) {
+            let pr = vld1q_s32(channels[0].as_ptr().add(i));
+            let pg = vld1q_s32(channels[1].as_ptr().add(i));
+            let pb = vld1q_s32(channels[2].as_ptr().add(i));
+
+            let r_mean = vshrq_n_s32::<1>(vaddq_s32(r, pr));
+            let dr = vsubq_s32(r, pr);
+            let dg = vsubq_s32(g, pg);
+            let db = vsubq_s32(b, pb);
+
+            let dr2 = vmulq_s32(vmulq_s32(vaddq_s32(w512, r_mean), dr), dr);
+            let dg2 = vmulq_s32(vmulq_s32(w4, dg), dg);
+            let db2 = vmulq_s32(vmulq_s32(vsubq_s32(w767, r_mean), db), db);
+            let dr2 = vmulq_f32(vcvtq_f32_s32(dr2), inv256);
+            let dg2 = vcvtq_f32_s32(dg2);
+            let db2 = vmulq_f32(vcvtq_f32_s32(db2), inv256);
+            let d = vdivq_f32(vaddq_f32(vaddq_f32(dr2, dg2), db2), three);
+
+            let lt = vcltq_f32(d, best_d);
+            best_d = vbslq_f32(lt, d, best_d);
+            best_i = vbslq_u32(lt, idx, best_i);
+            idx = vaddq_u32(idx, step);
+        }
+
+        let (nearest, distance) = reduce(best_d, best_i);
+        (nearest, distance, len)
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn reduce(best_d: float32x4_t, best_i: uint32x4_t) -> (usize, f32) {
+        let mut distances = [0f32; LANES];
+        let mut indices = [0u32; LANES];
+        vst1q_f32(distances.as_mut_ptr(), best_d);
+        vst1q_u32(indices.as_mut_ptr(), best_i);
+        reduce_lanes(distances, indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{scan, Rng};
+    use super::*;
+    use crate::color::{CieLab, RgbU8};
+
+    /// Every kernel the current CPU can run
+    fn kernels() -> Vec<Kernel> {
+        let mut kernels = vec![Kernel::Scalar];
+        if Kernel::detect() != Kernel::Scalar {
+            kernels.push(Kernel::detect());
+        }
+        kernels
+    }
+
+    fn check_euclidean(palette: &[[f32; 3]], color: [f32; 3]) {
+        let channels: [Vec<f32>; 3] = [0, 1, 2].map(|c| palette.iter().map(|p| p[c]).collect());
+        let channels = [&channels[0][..], &channels[1], &channels[2]];
+        let lab: Vec<CieLab> = palette.iter().map(|&p| CieLab(p)).collect();
+        let expected = scan(&lab, &CieLab(color));
+        for kernel in kernels() {
+            assert_eq!(
+                nearest_euclidean(kernel, channels, color),
+                Some(expected),
+                "{:?} with {} colors",
+                kernel,
+                palette.len()
+            );
+        }
+    }
+
+    fn check_redmean(palette: &[[u8; 3]], color: [u8; 3]) {
+        let channels: [Vec<i32>; 3] =
+            [0, 1, 2].map(|c| palette.iter().map(|p| i32::from(p[c])).collect());
+        let channels = [&channels[0][..], &channels[1], &channels[2]];
+        let rgb: Vec<RgbU8> = palette.iter().map(|&p| RgbU8(p)).collect();
+        let expected = scan(&rgb, &RgbU8(color));
+        for kernel in kernels() {
+            assert_eq!(
+                nearest_redmean(kernel, channels, color.map(i32::from)),
+                Some(expected),
+                "{:?} with {} colors",
+                kernel,
+                palette.len()
+            );
+        }
+    }
+
+    /// 1 and 256 colors, and lengths that aren't a multiple of the 4 or 8
+    /// lanes
+    const LENGTHS: [usize; 13] = [1, 2, 3, 4, 5, 7, 8, 9, 15, 17, 31, 255, 256];
+
+    #[test]
+    fn euclidean_matches_scan() {
+        let mut rng = Rng(0x9e37_79b9);
+        for len in LENGTHS {
+            let palette: Vec<[f32; 3]> = (0..len).map(|_| rng.lab()).collect();
+            for _ in 0..64 {
+                check_euclidean(&palette, rng.lab());
+            }
+        }
+    }
+
+    #[test]
+    fn redmean_matches_scan() {
+        let mut rng = Rng(0x85eb_ca6b);
+        for len in LENGTHS {
+            let palette: Vec<[u8; 3]> = (0..len).map(|_| rng.rgb()).collect();
+            for _ in 0..64 {
+                check_redmean(&palette, rng.rgb());
+            }
+        }
+    }
+
+    #[test]
+    fn ties_pick_lowest_index() {
+        for len in LENGTHS {
+            // Every color equally far, in every lane and the remainder
+            check_euclidean(&vec![[50.0, 10.0, -10.0]; len], [0.0; 3]);
+            check_redmean(&vec![[200, 100, 50]; len], [0; 3]);
+
+            // The nearest color repeated in different lanes and the
+            // remainder
+            let mut palette = vec![[255, 255, 255]; len];
+            let repeats = [len / 2, len / 4 + 1, len - 1].map(|i| i.min(len - 1));
+            for i in repeats {
+                palette[i] = [10, 20, 30];
+            }
+            check_redmean(&palette, [10, 20, 30]);
+            let lab: Vec<[f32; 3]> = palette
+                .iter()
+                .map(|&p| CieLab::from(image::Rgb(p)).0)
+                .collect();
+            check_euclidean(&lab, lab[repeats[0]]);
+
+            let first = *repeats.iter().min().unwrap();
+            let channels: [Vec<i32>; 3] =
+                [0, 1, 2].map(|c| palette.iter().map(|p| i32::from(p[c])).collect());
+            for kernel in kernels() {
+                let channels = [&channels[0][..], &channels[1], &channels[2]];
+                assert_eq!(
+                    nearest_redmean(kernel, channels, [10, 20, 30]),
+                    Some((first, 0.0))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty_palette() {
+        for kernel in kernels() {
+            assert_eq!(nearest_euclidean(kernel, [&[]; 3], [0.0; 3]), None);
+            assert_eq!(nearest_redmean(kernel, [&[]; 3], [0; 3]), None);
+        }
+    }
+}
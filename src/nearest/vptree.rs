@@ -0,0 +1,168 @@
+use super::NearestSearch;
+use crate::{color::RgbU8, Distance};
+
+/// Leaves hold up to this many points, which are scanned linearly
+const LEAF_SIZE: usize = 16;
+/// Slack added to the search radius to absorb floating point error
+const EPSILON: f32 = 1e-3;
+
+/// A true metric that never exceeds `Distance::distance`. The VP-tree prunes
+/// with it, which keeps searches exact even when `distance` itself doesn't
+/// satisfy the triangle inequality.
+pub trait Metric: Distance<Output = f32> {
+    fn metric(&self, other: &Self) -> f32;
+}
+
+impl Metric for RgbU8 {
+    fn metric(&self, other: &Self) -> f32 {
+        // Redmean weights red and blue by (512 + r_mean) / 256 and
+        // (767 - r_mean) / 256, which are both at least 2, so the weighted
+        // Euclidean distance with the smallest weights is a lower bound
+        let dr = f32::from(self[0]) - f32::from(other[0]);
+        let dg = f32::from(self[1]) - f32::from(other[1]);
+        let db = f32::from(self[2]) - f32::from(other[2]);
+        ((2.0 * dr * dr + 4.0 * dg * dg + 2.0 * db * db) / 3.0).sqrt()
+    }
+}
+
+/// Vantage-point tree, for distances that aren't Euclidean
+pub struct VpTree<T> {
+    nodes: Vec<Node<T>>,
+    /// Points reordered so that every leaf is a contiguous range. Within a
+    /// leaf, points are sorted by index
+    points: Vec<T>,
+    indices: Vec<u32>,
+}
+
+enum Node<T> {
+    /// Points with a metric distance to `point` of at most `radius` are
+    /// stored under the child directly following this node, the rest under
+    /// `outside`
+    Vantage {
+        point: T,
+        radius: f32,
+        outside: u32,
+    },
+    Leaf {
+        start: u32,
+        end: u32,
+    },
+}
+
+impl<T> VpTree<T>
+where
+    T: Copy + Sync + Metric,
+{
+    pub fn new(palette: &[T]) -> Self {
+        let mut points: Vec<_> = palette
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| (u32::try_from(i).unwrap(), p))
+            .collect();
+        let mut nodes = Vec::new();
+        if !points.is_empty() {
+            Self::build(&mut points, 0, &mut nodes);
+        }
+        VpTree {
+            nodes,
+            points: points.iter().map(|&(_, p)| p).collect(),
+            indices: points.iter().map(|&(i, _)| i).collect(),
+        }
+    }
+
+    fn build(points: &mut [(u32, T)], start: usize, nodes: &mut Vec<Node<T>>) {
+        if points.len() <= LEAF_SIZE {
+            // Scanning in index order keeps ties going to the lowest index
+            points.sort_unstable_by_key(|&(i, _)| i);
+            nodes.push(Node::Leaf {
+                start: u32::try_from(start).unwrap(),
+                end: u32::try_from(start + points.len()).unwrap(),
+            });
+            return;
+        }
+
+        // Use the first point as the vantage point, and split the remaining
+        // points at the median distance to it. The vantage point itself goes
+        // inside
+        let vantage = points[0].1;
+        let rest = &mut points[1..];
+        let mid = (rest.len() - 1) / 2;
+        rest.select_nth_unstable_by(mid, |a, b| {
+            vantage.metric(&a.1).total_cmp(&vantage.metric(&b.1))
+        });
+        let radius = vantage.metric(&rest[mid].1);
+
+        let id = nodes.len();
+        nodes.push(Node::Vantage {
+            point: vantage,
+            radius,
+            outside: 0,
+        });
+        let (inside, outside) = points.split_at_mut(mid + 2);
+        Self::build(inside, start, nodes);
+        let outside_id = u32::try_from(nodes.len()).unwrap();
+        if let Node::Vantage { outside, .. } = &mut nodes[id] {
+            *outside = outside_id;
+        }
+        Self::build(outside, start + mid + 2, nodes);
+    }
+
+    fn search(&self, node: usize, color: &T, best: &mut (u32, f32)) {
+        match self.nodes[node] {
+            Node::Leaf { start, end } => {
+                for i in start as usize..end as usize {
+                    let distance = color.distance2(&self.points[i]);
+                    let index = self.indices[i];
+                    if distance < best.1 || (distance == best.1 && index < best.0) {
+                        *best = (index, distance);
+                    }
+                }
+            }
+            Node::Vantage {
+                point,
+                radius,
+                outside,
+            } => {
+                let inside = node + 1;
+                let outside = outside as usize;
+                let d = color.metric(&point);
+                if d <= radius {
+                    self.search(inside, color, best);
+                    if d + tau(best.1) >= radius {
+                        self.search(outside, color, best);
+                    }
+                } else {
+                    self.search(outside, color, best);
+                    if d - tau(best.1) <= radius {
+                        self.search(inside, color, best);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Any point at least as near as the current best is within this metric
+/// distance of the query
+fn tau(best_distance2: f32) -> f32 {
+    best_distance2.sqrt() * (1.0 + EPSILON) + EPSILON
+}
+
+impl<T> NearestSearch<T> for VpTree<T>
+where
+    T: Copy + Sync + Metric,
+{
+    fn nearest(&self, color: &T) -> Option<usize> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut best = (u32::MAX, f32::INFINITY);
+        self.search(0, color, &mut best);
+        // Only happens if every distance is NaN
+        if best.0 == u32::MAX {
+            return Some(0);
+        }
+        Some(best.0 as usize)
+    }
+}
@@ -0,0 +1,176 @@
+use crate::color::RgbU8;
+
+// Constants from Anthony Dekker's NeuQuant, "Kohonen neural networks for
+// optimal colour quantization" (1994)
+const CYCLES: usize = 100;
+/// Primes used to step through the image, one of which doesn't divide the
+/// number of pixels
+const PRIMES: [usize; 4] = [499, 491, 487, 503];
+
+/// Network colors are stored with this many extra bits of precision
+const NET_BIAS_SHIFT: u32 = 4;
+const INT_BIAS_SHIFT: u32 = 16;
+const INT_BIAS: i32 = 1 << INT_BIAS_SHIFT;
+const GAMMA_SHIFT: u32 = 10;
+const BETA_SHIFT: u32 = 10;
+const BETA: i32 = INT_BIAS >> BETA_SHIFT;
+const BETA_GAMMA: i32 = INT_BIAS << (GAMMA_SHIFT - BETA_SHIFT);
+
+const RADIUS_BIAS_SHIFT: u32 = 6;
+const RADIUS_BIAS: i32 = 1 << RADIUS_BIAS_SHIFT;
+const RADIUS_DEC: i32 = 30;
+
+const ALPHA_BIAS_SHIFT: u32 = 10;
+const INIT_ALPHA: i32 = 1 << ALPHA_BIAS_SHIFT;
+const RAD_BIAS_SHIFT: u32 = 8;
+const RAD_BIAS: i32 = 1 << RAD_BIAS_SHIFT;
+const ALPHA_RAD_BIAS: i32 = 1 << (ALPHA_BIAS_SHIFT + RAD_BIAS_SHIFT);
+
+/// Self-organizing map over RGB. The neurons form a 1-D chain, and every
+/// sampled pixel pulls the closest neuron and its neighbours along the chain
+/// towards it
+struct Network {
+    neurons: Vec<[i32; 3]>,
+    /// How often each neuron wins, used to give rarely chosen neurons a
+    /// chance
+    freq: Vec<i32>,
+    bias: Vec<i32>,
+    radpower: Vec<i32>,
+}
+
+impl Network {
+    fn new(size: usize) -> Self {
+        let size_i32 = i32::try_from(size).unwrap();
+        Network {
+            // Start from a gray ramp
+            neurons: (0..size_i32)
+                .map(|i| [(i << (NET_BIAS_SHIFT + 8)) / size_i32; 3])
+                .collect(),
+            freq: vec![INT_BIAS / size_i32; size],
+            bias: vec![0; size],
+            radpower: Vec::new(),
+        }
+    }
+
+    /// Finds the closest neuron, updating the frequencies, and returns the
+    /// closest neuron after biasing
+    fn contest(&mut self, color: [i32; 3]) -> usize {
+        let mut best = (i32::MAX, 0);
+        let mut best_biased = (i32::MAX, 0);
+        for (i, neuron) in self.neurons.iter().enumerate() {
+            let distance: i32 = (0..3).map(|c| (neuron[c] - color[c]).abs()).sum();
+            if distance < best.0 {
+                best = (distance, i);
+            }
+            let biased = distance - (self.bias[i] >> (INT_BIAS_SHIFT - NET_BIAS_SHIFT));
+            if biased < best_biased.0 {
+                best_biased = (biased, i);
+            }
+            let beta_freq = self.freq[i] >> BETA_SHIFT;
+            self.freq[i] -= beta_freq;
+            self.bias[i] += beta_freq << GAMMA_SHIFT;
+        }
+        self.freq[best.1] += BETA;
+        self.bias[best.1] -= BETA_GAMMA;
+        best_biased.1
+    }
+
+    /// Moves neuron `i` towards `color` by `alpha / INIT_ALPHA`
+    fn alter_single(&mut self, alpha: i32, i: usize, color: [i32; 3]) {
+        for (n, c) in self.neurons[i].iter_mut().zip(color) {
+            *n -= alpha * (*n - c) / INIT_ALPHA;
+        }
+    }
+
+    /// Moves the neighbours of neuron `i` closer than `rad` towards `color`
+    fn alter_neighbours(&mut self, rad: usize, i: usize, color: [i32; 3]) {
+        let lo = (i + 1).saturating_sub(rad);
+        let hi = (i + rad).min(self.neurons.len());
+        for j in lo..hi {
+            if j == i {
+                continue;
+            }
+            let a = self.radpower[i.abs_diff(j)];
+            for (n, c) in self.neurons[j].iter_mut().zip(color) {
+                *n -= a * (*n - c) / ALPHA_RAD_BIAS;
+            }
+        }
+    }
+
+    fn set_radpower(&mut self, alpha: i32, rad: usize) {
+        let rad = i32::try_from(rad).unwrap();
+        self.radpower = (0..rad)
+            .map(|i| alpha * (((rad * rad - i * i) * RAD_BIAS) / (rad * rad)))
+            .collect();
+    }
+
+    fn learn(&mut self, pixels: &[RgbU8], sample_factor: u8) {
+        // Small images are always fully sampled
+        let sample_factor = if pixels.len() < PRIMES[3] {
+            1
+        } else {
+            usize::from(sample_factor)
+        };
+        let samples = pixels.len() / sample_factor;
+        let delta = (samples / CYCLES).max(1);
+        let alpha_dec = 30 + (i32::try_from(sample_factor).unwrap() - 1) / 3;
+
+        let mut alpha = INIT_ALPHA;
+        let mut radius = i32::try_from(self.neurons.len() >> 3).unwrap() * RADIUS_BIAS;
+        let to_rad = |radius: i32| match radius >> RADIUS_BIAS_SHIFT {
+            rad @ 2.. => rad as usize,
+            _ => 0,
+        };
+        let mut rad = to_rad(radius);
+        self.set_radpower(alpha, rad);
+
+        let step = PRIMES
+            .iter()
+            .copied()
+            .find(|&prime| !pixels.len().is_multiple_of(prime))
+            .unwrap_or(PRIMES[3]);
+
+        let mut pos = 0;
+        for i in 1..=samples {
+            let color = pixels[pos].0.map(|c| i32::from(c) << NET_BIAS_SHIFT);
+            let j = self.contest(color);
+            self.alter_single(alpha, j, color);
+            if rad > 0 {
+                self.alter_neighbours(rad, j, color);
+            }
+            pos = (pos + step) % pixels.len();
+
+            if i % delta == 0 {
+                alpha -= alpha / alpha_dec;
+                radius -= radius / RADIUS_DEC;
+                rad = to_rad(radius);
+                self.set_radpower(alpha, rad);
+            }
+        }
+    }
+}
+
+/// Returns a palette of `palette_size` colors trained with NeuQuant on every
+/// `sample_factor`th pixel. 1 gives the best quality, 30 is fastest
+pub fn palette(pixels: &[RgbU8], palette_size: usize, sample_factor: u8) -> Vec<RgbU8> {
+    assert!(
+        (1..=30).contains(&sample_factor),
+        "sample factor must be in 1..=30"
+    );
+    if pixels.is_empty() || palette_size == 0 {
+        return Vec::new();
+    }
+
+    let mut network = Network::new(palette_size);
+    network.learn(pixels, sample_factor);
+    let unbias = |n: i32| ((n + (1 << (NET_BIAS_SHIFT - 1))) >> NET_BIAS_SHIFT).clamp(0, 255) as u8;
+    let mut palette: Vec<RgbU8> = network
+        .neurons
+        .iter()
+        .map(|neuron| RgbU8(neuron.map(unbias)))
+        .collect();
+    // Images with few colors leave several neurons on the same color
+    palette.sort_unstable_by_key(|color| color.0);
+    palette.dedup();
+    palette
+}
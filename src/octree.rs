@@ -0,0 +1,167 @@
+use crate::color::RgbU8;
+
+/// Maximum number of leaves kept while pixels are being added. Memory use is
+/// bounded by this, no matter how many unique colors the image has
+const MAX_LEAVES: usize = 1 << 12;
+/// Leaves at this depth hold exactly one color
+const MAX_DEPTH: usize = 8;
+const NONE: u32 = 0;
+
+/// Octree color quantizer. Pixels are streamed in once, and whenever there
+/// are too many leaves the least populated node at the deepest level has its
+/// children merged into it
+pub struct Octree {
+    nodes: Vec<Node>,
+    /// Nodes freed by merging, which can be reused
+    free: Vec<u32>,
+    /// Internal nodes at each depth
+    reducible: [Vec<u32>; MAX_DEPTH],
+    leaves: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: [u32; 8],
+    /// Number and sum of all pixels under this node
+    count: u64,
+    sum: [u64; 3],
+    leaf: bool,
+}
+
+impl Octree {
+    pub fn new() -> Self {
+        // The root is never a leaf, so child index 0 can mean "no child"
+        let mut reducible: [Vec<u32>; MAX_DEPTH] = Default::default();
+        reducible[0].push(0);
+        Octree {
+            nodes: vec![Node::default()],
+            free: Vec::new(),
+            reducible,
+            leaves: 0,
+        }
+    }
+
+    pub fn add(&mut self, color: RgbU8) {
+        let mut node = 0;
+        for depth in 0..MAX_DEPTH {
+            self.nodes[node].count += 1;
+            for (sum, c) in self.nodes[node].sum.iter_mut().zip(color.0) {
+                *sum += u64::from(c);
+            }
+            if self.nodes[node].leaf {
+                return;
+            }
+
+            let shift = MAX_DEPTH - 1 - depth;
+            let child = color
+                .0
+                .iter()
+                .fold(0, |acc, &c| (acc << 1) | usize::from((c >> shift) & 1));
+            if self.nodes[node].children[child] == NONE {
+                let id = self.alloc(depth + 1);
+                self.nodes[node].children[child] = id;
+            }
+            node = self.nodes[node].children[child] as usize;
+        }
+
+        // Reached a leaf at the maximum depth
+        self.nodes[node].count += 1;
+        for (sum, c) in self.nodes[node].sum.iter_mut().zip(color.0) {
+            *sum += u64::from(c);
+        }
+
+        while self.leaves > MAX_LEAVES {
+            self.reduce(usize::MAX);
+        }
+    }
+
+    fn alloc(&mut self, depth: usize) -> u32 {
+        let node = Node {
+            leaf: depth == MAX_DEPTH,
+            ..Default::default()
+        };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id as usize] = node;
+                id
+            }
+            None => {
+                self.nodes.push(node);
+                u32::try_from(self.nodes.len() - 1).unwrap()
+            }
+        };
+
+        if depth == MAX_DEPTH {
+            self.leaves += 1;
+        } else {
+            self.reducible[depth].push(id);
+        }
+        id
+    }
+
+    /// Merges the children of the least populated node at the deepest level
+    /// into it, removing at most `excess` leaves. All of its children are
+    /// leaves, as any internal children would be at a deeper level
+    fn reduce(&mut self, excess: usize) {
+        let Some(level) = self.reducible.iter_mut().rev().find(|l| !l.is_empty()) else {
+            return;
+        };
+        let (i, &id) = level
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &id)| self.nodes[id as usize].count)
+            .unwrap();
+        let id = id as usize;
+
+        let mut children: Vec<_> = (0..8)
+            .filter(|&c| self.nodes[id].children[c] != NONE)
+            .collect();
+        if children.len() - 1 > excess {
+            // Merging every child would leave too few colors, so only merge
+            // the two least populated ones
+            children.sort_by_key(|&c| self.nodes[self.nodes[id].children[c] as usize].count);
+            let into = self.nodes[id].children[children[0]] as usize;
+            let from = std::mem::replace(&mut self.nodes[id].children[children[1]], NONE);
+            let Node { count, sum, .. } = self.nodes[from as usize].clone();
+            self.nodes[into].count += count;
+            for (a, b) in self.nodes[into].sum.iter_mut().zip(sum) {
+                *a += b;
+            }
+            self.free.push(from);
+            self.leaves -= 1;
+            return;
+        }
+
+        level.swap_remove(i);
+        let children = std::mem::take(&mut self.nodes[id].children);
+        for child in children.into_iter().filter(|&c| c != NONE) {
+            self.free.push(child);
+            self.leaves -= 1;
+        }
+        self.nodes[id].leaf = true;
+        self.leaves += 1;
+    }
+
+    /// Reduces the tree to at most `palette_size` leaves and returns their
+    /// mean colors
+    pub fn palette(mut self, palette_size: usize) -> Vec<RgbU8> {
+        let palette_size = palette_size.max(1);
+        while self.leaves > palette_size {
+            self.reduce(self.leaves - palette_size);
+        }
+
+        let mut palette = Vec::with_capacity(self.leaves);
+        let mut stack = vec![0];
+        while let Some(id) = stack.pop() {
+            let node = &self.nodes[id as usize];
+            if node.leaf {
+                palette.push(RgbU8(
+                    node.sum.map(|s| ((s + node.count / 2) / node.count) as u8),
+                ));
+            } else {
+                stack.extend(node.children.iter().rev().filter(|&&c| c != NONE));
+            }
+        }
+        palette
+    }
+}
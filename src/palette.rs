@@ -0,0 +1,570 @@
+//! Reads palettes made by other programs, for [`PaletteMethod::Fixed`], and
+//! writes palettes computed from images for use in other programs
+//!
+//! [`PaletteMethod::Fixed`]: crate::PaletteMethod::Fixed
+
+mod names;
+
+use crate::{
+    color::{CieLab, Itp, RgbU8},
+    compress::{self, ColorCounts, Options, OptionsError},
+    export::{ExportError, Paletted},
+    inspect::hex,
+    Image,
+};
+use clap::ValueEnum;
+use image::Rgb;
+use std::collections::HashSet;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Largest number of colors in a palette
+const MAX_COLORS: usize = 256;
+
+/// File formats palettes can be read from or written to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum PaletteFormat {
+    /// GIMP palette
+    Gpl,
+    /// Adobe Color Table, 256 RGB triples optionally followed by the number
+    /// of colors
+    Act,
+    /// Adobe Swatch Exchange
+    Ase,
+    /// Colors with their names, population share and coordinates in every
+    /// color space. Can only be written
+    Json,
+    /// Colors written as `#rrggbb`, one or more per line
+    Hex,
+    /// The palette of an indexed-color PNG. Written as an image one pixel
+    /// high with one pixel per color
+    Png,
+    /// The global color table of a GIF, or the palette of its first frame.
+    /// Tables are padded to a power of two, so they may hold extra colors.
+    /// Written like PNG
+    Gif,
+}
+
+impl PaletteFormat {
+    /// Guesses the format from the extension. Unknown extensions are read as
+    /// hex lists
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("gpl") => PaletteFormat::Gpl,
+            Some("act") => PaletteFormat::Act,
+            Some("ase") => PaletteFormat::Ase,
+            Some("json") => PaletteFormat::Json,
+            Some("png") => PaletteFormat::Png,
+            Some("gif") => PaletteFormat::Gif,
+            _ => PaletteFormat::Hex,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            PaletteFormat::Gpl => "gpl",
+            PaletteFormat::Act => "act",
+            PaletteFormat::Ase => "ase",
+            PaletteFormat::Json => "json",
+            PaletteFormat::Hex => "hex",
+            PaletteFormat::Png => "png",
+            PaletteFormat::Gif => "gif",
+        }
+    }
+}
+
+/// Why a palette couldn't be read
+#[derive(Debug)]
+pub enum PaletteError {
+    /// The file doesn't follow its format, with a description of the problem
+    Invalid(String),
+    Png(png::DecodingError),
+    Gif(gif::DecodingError),
+    /// The image has no palette
+    NotIndexed,
+    Empty,
+    TooManyColors(usize),
+    /// The format can't be read
+    WriteOnly(PaletteFormat),
+    Io(io::Error),
+    Export(ExportError),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Invalid(problem) => write!(f, "invalid palette: {}", problem),
+            PaletteError::Png(err) => write!(f, "cannot decode PNG: {}", err),
+            PaletteError::Gif(err) => write!(f, "cannot decode GIF: {}", err),
+            PaletteError::NotIndexed => write!(f, "image has no palette"),
+            PaletteError::Empty => write!(f, "palette has no colors"),
+            PaletteError::TooManyColors(count) => write!(
+                f,
+                "palette has {} colors, at most {} are allowed",
+                count, MAX_COLORS
+            ),
+            PaletteError::WriteOnly(format) => {
+                write!(f, "{:?} palettes can only be written", format)
+            }
+            PaletteError::Io(err) => write!(f, "cannot write palette: {}", err),
+            PaletteError::Export(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<io::Error> for PaletteError {
+    fn from(err: io::Error) -> Self {
+        PaletteError::Io(err)
+    }
+}
+
+impl From<ExportError> for PaletteError {
+    fn from(err: ExportError) -> Self {
+        PaletteError::Export(err)
+    }
+}
+
+/// A palette color and how it is used
+#[derive(Debug, Copy, Clone)]
+pub struct Swatch {
+    pub color: Rgb<u8>,
+    /// Name of the closest CSS color
+    pub name: &'static str,
+    /// Fraction of the pixels mapped to the color
+    pub share: f64,
+}
+
+impl Swatch {
+    pub fn cielab(&self) -> CieLab {
+        self.color.into()
+    }
+
+    pub fn itp(&self) -> Itp {
+        self.color.into()
+    }
+}
+
+/// Returns the 64-bit FNV-1a hash of the palette colors, which referenced
+/// mode files store in place of the palette
+pub fn hash(palette: &[Rgb<u8>]) -> u64 {
+    palette
+        .iter()
+        .flat_map(|color| color.0)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Runs only the palette stage of [`compress::compress`] with the same
+/// options, and returns the palette with the most used colors first
+pub fn extract(img: &Image, options: &Options) -> Result<Vec<Swatch>, OptionsError> {
+    let paletted = compress::palettize(img, options)?;
+    let mut counts = vec![0u64; paletted.palette.len()];
+    for &index in &paletted.indices {
+        counts[usize::from(index)] += 1;
+    }
+    Ok(swatches(paletted.palette.into_iter().zip(counts).collect()))
+}
+
+/// Computes one palette for a set of images from their color counts with
+/// [`compress::shared_palette`], most used colors first
+pub fn extract_shared(
+    counts: &ColorCounts,
+    options: &Options,
+) -> Result<Vec<Swatch>, OptionsError> {
+    Ok(swatches(compress::shared_palette(counts, options)?))
+}
+
+/// Names colors and sorts them by their number of pixels, most used first
+fn swatches(colors: Vec<(Rgb<u8>, u64)>) -> Vec<Swatch> {
+    let pixel_count = colors.iter().map(|&(_, count)| count).sum::<u64>().max(1) as f64;
+    let mut swatches: Vec<Swatch> = colors
+        .into_iter()
+        .map(|(color, count)| Swatch {
+            color,
+            name: names::nearest(color),
+            share: count as f64 / pixel_count,
+        })
+        .collect();
+    swatches.sort_by(|a, b| b.share.total_cmp(&a.share));
+    swatches
+}
+
+/// Writes a palette, `title` is stored in formats that have one
+pub fn write<W: Write>(
+    swatches: &[Swatch],
+    format: PaletteFormat,
+    title: &str,
+    mut w: W,
+) -> Result<(), PaletteError> {
+    match swatches.len() {
+        0 => return Err(PaletteError::Empty),
+        count if count > MAX_COLORS => return Err(PaletteError::TooManyColors(count)),
+        _ => {}
+    }
+    match format {
+        PaletteFormat::Gpl => w.write_all(gpl(swatches, title).as_bytes())?,
+        PaletteFormat::Act => w.write_all(&act(swatches))?,
+        PaletteFormat::Ase => w.write_all(&ase(swatches))?,
+        PaletteFormat::Json => w.write_all(json(swatches, title).as_bytes())?,
+        PaletteFormat::Hex => {
+            for swatch in swatches {
+                writeln!(w, "{}", hex(swatch.color))?;
+            }
+        }
+        PaletteFormat::Png | PaletteFormat::Gif => {
+            let paletted = Paletted {
+                width: swatches.len() as u32,
+                height: 1,
+                palette: swatches.iter().map(|swatch| swatch.color).collect(),
+                indices: (0..=u8::MAX).take(swatches.len()).collect(),
+            };
+            if format == PaletteFormat::Png {
+                paletted.write_png(w)?;
+            } else {
+                paletted.write_gif(w)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn gpl(swatches: &[Swatch], title: &str) -> String {
+    let mut text = format!("GIMP Palette\nName: {}\nColumns: 16\n#\n", title);
+    for swatch in swatches {
+        let [r, g, b] = swatch.color.0;
+        writeln!(
+            text,
+            "{:3} {:3} {:3}\t{} ({:.2}%)",
+            r,
+            g,
+            b,
+            swatch.name,
+            swatch.share * 100.0
+        )
+        .unwrap();
+    }
+    text
+}
+
+fn act(swatches: &[Swatch]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = swatches.iter().flat_map(|swatch| swatch.color.0).collect();
+    bytes.resize(MAX_COLORS * 3, 0);
+    bytes.extend_from_slice(&(swatches.len() as u16).to_be_bytes());
+    // No transparent color
+    bytes.extend_from_slice(&u16::MAX.to_be_bytes());
+    bytes
+}
+
+/// Block type of a color entry in an ASE file
+const ASE_COLOR: u16 = 0x0001;
+/// Color type of colors that aren't global or spot colors
+const ASE_NORMAL: u16 = 2;
+
+fn ase(swatches: &[Swatch]) -> Vec<u8> {
+    let mut bytes = b"ASEF".to_vec();
+    bytes.extend_from_slice(&1u16.to_be_bytes());
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+    bytes.extend_from_slice(&(swatches.len() as u32).to_be_bytes());
+    for swatch in swatches {
+        // Names are null-terminated UTF-16 with their length in code units
+        let mut name: Vec<u16> = swatch.name.encode_utf16().collect();
+        name.push(0);
+
+        let mut block = Vec::new();
+        block.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        block.extend(name.iter().flat_map(|unit| unit.to_be_bytes()));
+        block.extend_from_slice(b"RGB ");
+        for channel in swatch.color.0 {
+            block.extend_from_slice(&(f32::from(channel) / 255.0).to_be_bytes());
+        }
+        block.extend_from_slice(&ASE_NORMAL.to_be_bytes());
+
+        bytes.extend_from_slice(&ASE_COLOR.to_be_bytes());
+        bytes.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&block);
+    }
+    bytes
+}
+
+fn json(swatches: &[Swatch], title: &str) -> String {
+    let mut json = String::from("{\"name\":\"");
+    for c in title.chars() {
+        match c {
+            '"' | '\\' => write!(json, "\\{}", c),
+            c if c.is_control() => write!(json, "\\u{:04x}", u32::from(c)),
+            c => write!(json, "{}", c),
+        }
+        .unwrap();
+    }
+    json.push_str("\",\"colors\":[");
+    for (i, swatch) in swatches.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        let [r, g, b] = swatch.color.0;
+        let [l, a, b_] = swatch.cielab().0;
+        let [i_, t, p] = swatch.itp().0;
+        write!(
+            json,
+            "{}{{\"hex\":\"{}\",\"name\":\"{}\",\"share\":{},\"rgb\":[{},{},{}],\
+             \"cielab\":[{:.4},{:.4},{:.4}],\"itp\":[{:.6},{:.6},{:.6}]}}",
+            separator,
+            hex(swatch.color),
+            swatch.name,
+            swatch.share,
+            r,
+            g,
+            b,
+            l,
+            a,
+            b_,
+            i_,
+            t,
+            p
+        )
+        .unwrap();
+    }
+    json.push_str("]}");
+    json
+}
+
+/// Reads a palette of 1 to 256 colors. Repeated colors are dropped
+pub fn read(bytes: &[u8], format: PaletteFormat) -> Result<Vec<Rgb<u8>>, PaletteError> {
+    let mut colors = match format {
+        PaletteFormat::Gpl => read_gpl(text(bytes)?)?,
+        PaletteFormat::Act => read_act(bytes)?,
+        PaletteFormat::Ase => read_ase(bytes)?,
+        PaletteFormat::Json => return Err(PaletteError::WriteOnly(format)),
+        PaletteFormat::Hex => read_hex(text(bytes)?)?,
+        PaletteFormat::Png => read_png(bytes)?,
+        PaletteFormat::Gif => read_gif(bytes)?,
+    };
+
+    let mut seen = HashSet::new();
+    colors.retain(|&color| seen.insert(color));
+    match colors.len() {
+        0 => Err(PaletteError::Empty),
+        count if count > MAX_COLORS => Err(PaletteError::TooManyColors(count)),
+        _ => Ok(colors),
+    }
+}
+
+fn text(bytes: &[u8]) -> Result<&str, PaletteError> {
+    std::str::from_utf8(bytes).map_err(|_| PaletteError::Invalid("not UTF-8 text".to_owned()))
+}
+
+fn read_gpl(text: &str) -> Result<Vec<Rgb<u8>>, PaletteError> {
+    let mut lines = text.lines().enumerate();
+    if lines
+        .next()
+        .is_none_or(|(_, line)| line.trim() != "GIMP Palette")
+    {
+        return Err(PaletteError::Invalid(
+            "missing GIMP Palette header".to_owned(),
+        ));
+    }
+
+    let mut colors = Vec::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        // The color may be followed by a name
+        let channels: Option<Vec<u8>> = line
+            .split_whitespace()
+            .take(3)
+            .map(|channel| channel.parse().ok())
+            .collect();
+        match channels.as_deref() {
+            Some(&[r, g, b]) => colors.push(Rgb([r, g, b])),
+            _ => {
+                return Err(PaletteError::Invalid(format!(
+                    "line {} is not a color: {}",
+                    i + 1,
+                    line
+                )))
+            }
+        }
+    }
+    Ok(colors)
+}
+
+fn read_act(bytes: &[u8]) -> Result<Vec<Rgb<u8>>, PaletteError> {
+    let count = match bytes.len() {
+        768 => MAX_COLORS,
+        // Newer files store the number of colors and a transparent index
+        772 => usize::from(u16::from_be_bytes([bytes[768], bytes[769]])).min(MAX_COLORS),
+        len => {
+            return Err(PaletteError::Invalid(format!(
+                "color tables are 768 or 772 bytes, not {}",
+                len
+            )))
+        }
+    };
+    Ok(bytes[..count * 3]
+        .chunks_exact(3)
+        .map(|c| Rgb([c[0], c[1], c[2]]))
+        .collect())
+}
+
+/// Reads the RGB, LAB and gray colors of an ASE file. Groups are flattened
+/// and CMYK colors are converted naively
+fn read_ase(bytes: &[u8]) -> Result<Vec<Rgb<u8>>, PaletteError> {
+    let invalid = || PaletteError::Invalid("truncated swatch file".to_owned());
+    let u16_at = |pos: usize| -> Result<u16, PaletteError> {
+        let bytes = bytes.get(pos..pos + 2).ok_or_else(invalid)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let u32_at = |pos: usize| -> Result<u32, PaletteError> {
+        let bytes = bytes.get(pos..pos + 4).ok_or_else(invalid)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    if !bytes.starts_with(b"ASEF") {
+        return Err(PaletteError::Invalid("missing ASEF signature".to_owned()));
+    }
+    let block_count = u32_at(8)?;
+    let mut pos = 12;
+    let mut colors = Vec::new();
+    for _ in 0..block_count {
+        let block_type = u16_at(pos)?;
+        let len = u32_at(pos + 2)? as usize;
+        let start = pos + 6;
+        let block = bytes.get(start..start + len).ok_or_else(invalid)?;
+        pos = start + len;
+        if block_type != ASE_COLOR {
+            continue;
+        }
+
+        let name_len = usize::from(u16_at(start)?);
+        let model_start = 2 + name_len * 2;
+        let model = block
+            .get(model_start..model_start + 4)
+            .ok_or_else(invalid)?;
+        let values: Vec<f32> = block[model_start + 4..]
+            .chunks_exact(4)
+            .map(|v| f32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+            .collect();
+        let rgb = match (model, &values[..]) {
+            (b"RGB ", &[r, g, b, ..]) => [r, g, b],
+            (b"Gray", &[gray, ..]) => [gray; 3],
+            (b"CMYK", &[c, m, y, k, ..]) => [c, m, y].map(|x| (1.0 - x) * (1.0 - k)),
+            (b"LAB ", &[l, a, b, ..]) => {
+                let color = RgbU8::from(CieLab([l * 100.0, a, b]));
+                color.0.map(|x| f32::from(x) / 255.0)
+            }
+            _ => {
+                return Err(PaletteError::Invalid(format!(
+                    "unknown color model {}",
+                    String::from_utf8_lossy(model)
+                )))
+            }
+        };
+        colors.push(Rgb(rgb.map(|x| (x.clamp(0.0, 1.0) * 255.0).round() as u8)));
+    }
+    Ok(colors)
+}
+
+/// Reads `#rrggbb` or `rrggbb` colors separated by whitespace or commas.
+/// Paint.NET style `aarrggbb` colors are accepted with alpha ignored, and
+/// lines starting with `;` or `//` are comments
+fn read_hex(text: &str) -> Result<Vec<Rgb<u8>>, PaletteError> {
+    let mut colors = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with(';') || line.starts_with("//") {
+            continue;
+        }
+        for word in line.split(|c: char| c.is_whitespace() || c == ',') {
+            let digits = word.trim_start_matches('#');
+            if digits.is_empty() {
+                continue;
+            }
+            let value = match digits.len() {
+                6 | 8 => u32::from_str_radix(digits, 16).ok(),
+                _ => None,
+            }
+            .ok_or_else(|| PaletteError::Invalid(format!("not a hex color: {}", word)))?;
+            let [_, r, g, b] = value.to_be_bytes();
+            colors.push(Rgb([r, g, b]));
+        }
+    }
+    Ok(colors)
+}
+
+fn read_png(bytes: &[u8]) -> Result<Vec<Rgb<u8>>, PaletteError> {
+    let reader = png::Decoder::new(bytes)
+        .read_info()
+        .map_err(PaletteError::Png)?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed {
+        return Err(PaletteError::NotIndexed);
+    }
+    let palette = info.palette.as_ref().ok_or(PaletteError::NotIndexed)?;
+    Ok(rgb_triples(palette))
+}
+
+fn read_gif(bytes: &[u8]) -> Result<Vec<Rgb<u8>>, PaletteError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(bytes).map_err(PaletteError::Gif)?;
+    if let Some(global) = decoder.global_palette() {
+        return Ok(rgb_triples(global));
+    }
+    let frame = decoder
+        .read_next_frame()
+        .map_err(PaletteError::Gif)?
+        .ok_or(PaletteError::NotIndexed)?;
+    let palette = frame.palette.as_deref().ok_or(PaletteError::NotIndexed)?;
+    Ok(rgb_triples(palette))
+}
+
+fn rgb_triples(bytes: &[u8]) -> Vec<Rgb<u8>> {
+    bytes
+        .chunks_exact(3)
+        .map(|c| Rgb([c[0], c[1], c[2]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read_round_trip() {
+        let swatches: Vec<Swatch> = (0..=255u8)
+            .map(|i| {
+                let color = Rgb([i, i.wrapping_mul(37), 255 - i.wrapping_mul(101)]);
+                Swatch {
+                    color,
+                    name: names::nearest(color),
+                    share: 1.0 / 256.0,
+                }
+            })
+            .collect();
+        let colors: Vec<Rgb<u8>> = swatches.iter().map(|swatch| swatch.color).collect();
+        for format in [
+            PaletteFormat::Gpl,
+            PaletteFormat::Act,
+            PaletteFormat::Ase,
+            PaletteFormat::Hex,
+        ] {
+            for count in [1, 5, 256] {
+                let mut bytes = Vec::new();
+                write(&swatches[..count], format, "test", &mut bytes).unwrap();
+                let read = read(&bytes, format).unwrap();
+                assert_eq!(read, colors[..count], "{:?}, {} colors", format, count);
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! CSS color names, for naming palette colors
+
+use crate::{color::CieLab, Distance};
+use image::Rgb;
+
+const NAMES: [(&str, [u8; 3]); 64] = [
+    ("black", [0x00, 0x00, 0x00]),
+    ("dimgray", [0x69, 0x69, 0x69]),
+    ("gray", [0x80, 0x80, 0x80]),
+    ("darkgray", [0xa9, 0xa9, 0xa9]),
+    ("silver", [0xc0, 0xc0, 0xc0]),
+    ("lightgray", [0xd3, 0xd3, 0xd3]),
+    ("white", [0xff, 0xff, 0xff]),
+    ("slategray", [0x70, 0x80, 0x90]),
+    ("darkslategray", [0x2f, 0x4f, 0x4f]),
+    ("maroon", [0x80, 0x00, 0x00]),
+    ("darkred", [0x8b, 0x00, 0x00]),
+    ("red", [0xff, 0x00, 0x00]),
+    ("firebrick", [0xb2, 0x22, 0x22]),
+    ("crimson", [0xdc, 0x14, 0x3c]),
+    ("salmon", [0xfa, 0x80, 0x72]),
+    ("rosybrown", [0xbc, 0x8f, 0x8f]),
+    ("pink", [0xff, 0xc0, 0xcb]),
+    ("hotpink", [0xff, 0x69, 0xb4]),
+    ("deeppink", [0xff, 0x14, 0x93]),
+    ("purple", [0x80, 0x00, 0x80]),
+    ("magenta", [0xff, 0x00, 0xff]),
+    ("violet", [0xee, 0x82, 0xee]),
+    ("orchid", [0xda, 0x70, 0xd6]),
+    ("plum", [0xdd, 0xa0, 0xdd]),
+    ("lavender", [0xe6, 0xe6, 0xfa]),
+    ("indigo", [0x4b, 0x00, 0x82]),
+    ("midnightblue", [0x19, 0x19, 0x70]),
+    ("navy", [0x00, 0x00, 0x80]),
+    ("darkblue", [0x00, 0x00, 0x8b]),
+    ("blue", [0x00, 0x00, 0xff]),
+    ("royalblue", [0x41, 0x69, 0xe1]),
+    ("steelblue", [0x46, 0x82, 0xb4]),
+    ("skyblue", [0x87, 0xce, 0xeb]),
+    ("lightblue", [0xad, 0xd8, 0xe6]),
+    ("cyan", [0x00, 0xff, 0xff]),
+    ("turquoise", [0x40, 0xe0, 0xd0]),
+    ("teal", [0x00, 0x80, 0x80]),
+    ("darkgreen", [0x00, 0x64, 0x00]),
+    ("green", [0x00, 0x80, 0x00]),
+    ("forestgreen", [0x22, 0x8b, 0x22]),
+    ("seagreen", [0x2e, 0x8b, 0x57]),
+    ("lime", [0x00, 0xff, 0x00]),
+    ("lightgreen", [0x90, 0xee, 0x90]),
+    ("olive", [0x80, 0x80, 0x00]),
+    ("olivedrab", [0x6b, 0x8e, 0x23]),
+    ("yellowgreen", [0x9a, 0xcd, 0x32]),
+    ("yellow", [0xff, 0xff, 0x00]),
+    ("khaki", [0xf0, 0xe6, 0x8c]),
+    ("gold", [0xff, 0xd7, 0x00]),
+    ("goldenrod", [0xda, 0xa5, 0x20]),
+    ("orange", [0xff, 0xa5, 0x00]),
+    ("darkorange", [0xff, 0x8c, 0x00]),
+    ("coral", [0xff, 0x7f, 0x50]),
+    ("tomato", [0xff, 0x63, 0x47]),
+    ("chocolate", [0xd2, 0x69, 0x1e]),
+    ("peru", [0xcd, 0x85, 0x3f]),
+    ("sienna", [0xa0, 0x52, 0x2d]),
+    ("saddlebrown", [0x8b, 0x45, 0x13]),
+    ("brown", [0xa5, 0x2a, 0x2a]),
+    ("tan", [0xd2, 0xb4, 0x8c]),
+    ("wheat", [0xf5, 0xde, 0xb3]),
+    ("beige", [0xf5, 0xf5, 0xdc]),
+    ("ivory", [0xff, 0xff, 0xf0]),
+    ("mintcream", [0xf5, 0xff, 0xfa]),
+];
+
+/// Returns the name of the closest CSS color in CIELAB
+pub fn nearest(color: Rgb<u8>) -> &'static str {
+    let lab = CieLab::from(color);
+    NAMES
+        .iter()
+        .map(|&(name, rgb)| (name, lab.distance2(&CieLab::from(Rgb(rgb)))))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+        .0
+}
@@ -0,0 +1,101 @@
+//! Data-parallel helpers. With the `parallel` feature these run on rayon's
+//! thread pool, otherwise they fall back to plain iterators. Work is always
+//! split into the same fixed-size chunks, so results are bit-identical either
+//! way.
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Number of items folded by a single task
+const CHUNK_SIZE: usize = 1 << 14;
+
+/// Maps every item, preserving order
+#[cfg(feature = "parallel")]
+pub fn map<T, U, F>(items: &[T], f: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync + Send,
+{
+    items.par_iter().map(f).collect()
+}
+
+/// Maps every item, preserving order
+#[cfg(not(feature = "parallel"))]
+pub fn map<T, U, F>(items: &[T], f: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync + Send,
+{
+    items.iter().map(f).collect()
+}
+
+/// Folds each chunk of items separately and returns the results in chunk
+/// order
+#[cfg(feature = "parallel")]
+pub fn fold_chunks<T, A, I, F>(items: &[T], init: I, fold: F) -> Vec<A>
+where
+    T: Sync,
+    A: Send,
+    I: Fn() -> A + Sync + Send,
+    F: Fn(A, &T) -> A + Sync + Send,
+{
+    items
+        .par_chunks(CHUNK_SIZE)
+        .map(|chunk| chunk.iter().fold(init(), &fold))
+        .collect()
+}
+
+/// Folds each chunk of items separately and returns the results in chunk
+/// order
+#[cfg(not(feature = "parallel"))]
+pub fn fold_chunks<T, A, I, F>(items: &[T], init: I, fold: F) -> Vec<A>
+where
+    T: Sync,
+    A: Send,
+    I: Fn() -> A + Sync + Send,
+    F: Fn(A, &T) -> A + Sync + Send,
+{
+    items
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| chunk.iter().fold(init(), &fold))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Float sums depend on the order of additions, so they show whether
+    /// the chunks are the same
+    fn sums(items: &[f32]) -> Vec<f32> {
+        fold_chunks(items, || 0.0, |acc, &x| acc + x)
+    }
+
+    #[test]
+    fn fold_chunks_matches_sequential_fold() {
+        let items: Vec<f32> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 8) as f32 / 7.0)
+            .collect();
+        let expected: Vec<f32> = items
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| chunk.iter().fold(0.0, |acc, &x| acc + x))
+            .collect();
+        assert_eq!(sums(&items), expected);
+
+        #[cfg(feature = "parallel")]
+        for threads in [1, 3, 8] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            assert_eq!(
+                pool.install(|| sums(&items)),
+                expected,
+                "{} threads",
+                threads
+            );
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Xiaolin Wu's greedy orthogonal bipartition quantizer
+//! (Graphics Gems II, "Efficient Statistical Computations for Optimal Color
+//! Quantization").
+
+use crate::color::RgbU8;
+
+/// Each channel is reduced to 5 bits, with an extra row of zeros in front so
+/// that cumulative moments need no bounds checks
+const SIDE: usize = 33;
+
+#[derive(Debug, Copy, Clone)]
+enum Axis {
+    Red,
+    Green,
+    Blue,
+}
+
+/// A box in the 5-bit color cube. Lower bounds are exclusive and upper bounds
+/// inclusive
+#[derive(Debug, Copy, Clone, Default)]
+struct ColorBox {
+    r0: usize,
+    r1: usize,
+    g0: usize,
+    g1: usize,
+    b0: usize,
+    b1: usize,
+}
+
+impl ColorBox {
+    fn volume(&self) -> usize {
+        (self.r1 - self.r0) * (self.g1 - self.g0) * (self.b1 - self.b0)
+    }
+}
+
+/// Cumulative moments of the color histogram: `moment[r][g][b]` is the sum
+/// over every cell `(r', g', b') <= (r, g, b)`
+struct Moments {
+    weight: Vec<i64>,
+    red: Vec<i64>,
+    green: Vec<i64>,
+    blue: Vec<i64>,
+    squares: Vec<f64>,
+}
+
+fn index(r: usize, g: usize, b: usize) -> usize {
+    (r * SIDE + g) * SIDE + b
+}
+
+impl Moments {
+    fn new(pixels: &[RgbU8]) -> Self {
+        let mut moments = Moments {
+            weight: vec![0; SIDE * SIDE * SIDE],
+            red: vec![0; SIDE * SIDE * SIDE],
+            green: vec![0; SIDE * SIDE * SIDE],
+            blue: vec![0; SIDE * SIDE * SIDE],
+            squares: vec![0.0; SIDE * SIDE * SIDE],
+        };
+
+        for pixel in pixels {
+            let [r, g, b] = pixel.0.map(|c| usize::from(c >> 3) + 1);
+            let i = index(r, g, b);
+            let [pr, pg, pb] = pixel.0.map(i64::from);
+            moments.weight[i] += 1;
+            moments.red[i] += pr;
+            moments.green[i] += pg;
+            moments.blue[i] += pb;
+            moments.squares[i] += (pr * pr + pg * pg + pb * pb) as f64;
+        }
+
+        cumulate(&mut moments.weight);
+        cumulate(&mut moments.red);
+        cumulate(&mut moments.green);
+        cumulate(&mut moments.blue);
+        cumulate(&mut moments.squares);
+        moments
+    }
+}
+
+/// Turns a histogram into cumulative sums
+fn cumulate<T>(m: &mut [T])
+where
+    T: Copy + Default + std::ops::Add<Output = T> + std::ops::AddAssign,
+{
+    for r in 1..SIDE {
+        let mut area = [T::default(); SIDE];
+        for g in 1..SIDE {
+            let mut line = T::default();
+            for (b, area) in area.iter_mut().enumerate().skip(1) {
+                let i = index(r, g, b);
+                line += m[i];
+                *area += line;
+                m[i] = m[index(r - 1, g, b)] + *area;
+            }
+        }
+    }
+}
+
+/// Sum of a moment over a box
+fn volume<T>(cube: &ColorBox, m: &[T]) -> T
+where
+    T: Copy + std::ops::Add<Output = T> + std::ops::Sub<Output = T>,
+{
+    m[index(cube.r1, cube.g1, cube.b1)]
+        - m[index(cube.r1, cube.g1, cube.b0)]
+        - m[index(cube.r1, cube.g0, cube.b1)]
+        + m[index(cube.r1, cube.g0, cube.b0)]
+        - m[index(cube.r0, cube.g1, cube.b1)]
+        + m[index(cube.r0, cube.g1, cube.b0)]
+        + m[index(cube.r0, cube.g0, cube.b1)]
+        - m[index(cube.r0, cube.g0, cube.b0)]
+}
+
+/// Part of `volume` that doesn't depend on the box's upper bound along `axis`
+fn bottom(cube: &ColorBox, axis: Axis, m: &[i64]) -> i64 {
+    match axis {
+        Axis::Red => {
+            -m[index(cube.r0, cube.g1, cube.b1)]
+                + m[index(cube.r0, cube.g1, cube.b0)]
+                + m[index(cube.r0, cube.g0, cube.b1)]
+                - m[index(cube.r0, cube.g0, cube.b0)]
+        }
+        Axis::Green => {
+            -m[index(cube.r1, cube.g0, cube.b1)]
+                + m[index(cube.r1, cube.g0, cube.b0)]
+                + m[index(cube.r0, cube.g0, cube.b1)]
+                - m[index(cube.r0, cube.g0, cube.b0)]
+        }
+        Axis::Blue => {
+            -m[index(cube.r1, cube.g1, cube.b0)]
+                + m[index(cube.r1, cube.g0, cube.b0)]
+                + m[index(cube.r0, cube.g1, cube.b0)]
+                - m[index(cube.r0, cube.g0, cube.b0)]
+        }
+    }
+}
+
+/// Remainder of `volume` with the upper bound along `axis` set to `pos`
+fn top(cube: &ColorBox, axis: Axis, pos: usize, m: &[i64]) -> i64 {
+    match axis {
+        Axis::Red => {
+            m[index(pos, cube.g1, cube.b1)]
+                - m[index(pos, cube.g1, cube.b0)]
+                - m[index(pos, cube.g0, cube.b1)]
+                + m[index(pos, cube.g0, cube.b0)]
+        }
+        Axis::Green => {
+            m[index(cube.r1, pos, cube.b1)]
+                - m[index(cube.r1, pos, cube.b0)]
+                - m[index(cube.r0, pos, cube.b1)]
+                + m[index(cube.r0, pos, cube.b0)]
+        }
+        Axis::Blue => {
+            m[index(cube.r1, cube.g1, pos)]
+                - m[index(cube.r1, cube.g0, pos)]
+                - m[index(cube.r0, cube.g1, pos)]
+                + m[index(cube.r0, cube.g0, pos)]
+        }
+    }
+}
+
+impl Moments {
+    /// Weighted variance of the colors in a box
+    fn variance(&self, cube: &ColorBox) -> f64 {
+        let r = volume(cube, &self.red) as f64;
+        let g = volume(cube, &self.green) as f64;
+        let b = volume(cube, &self.blue) as f64;
+        let squares = volume(cube, &self.squares);
+        let weight = volume(cube, &self.weight) as f64;
+        squares - (r * r + g * g + b * b) / weight
+    }
+
+    /// Finds the cut along `axis` that minimizes the summed variance of the
+    /// two halves. Returns the score to maximize and the cut position
+    fn maximize(&self, cube: &ColorBox, axis: Axis, whole: [i64; 4]) -> (f64, Option<usize>) {
+        let (first, last) = match axis {
+            Axis::Red => (cube.r0 + 1, cube.r1),
+            Axis::Green => (cube.g0 + 1, cube.g1),
+            Axis::Blue => (cube.b0 + 1, cube.b1),
+        };
+        let moments = [&self.red, &self.green, &self.blue, &self.weight];
+        let base = moments.map(|m| bottom(cube, axis, m));
+
+        let mut max = 0.0;
+        let mut cut = None;
+        for pos in first..last {
+            let half: [i64; 4] =
+                std::array::from_fn(|i| base[i] + top(cube, axis, pos, moments[i]));
+            if half[3] == 0 {
+                continue;
+            }
+            let other: [i64; 4] = std::array::from_fn(|i| whole[i] - half[i]);
+            if other[3] == 0 {
+                continue;
+            }
+
+            let score = |[r, g, b, w]: [i64; 4]| {
+                let [r, g, b, w] = [r, g, b, w].map(|x| x as f64);
+                (r * r + g * g + b * b) / w
+            };
+            let temp = score(half) + score(other);
+            if temp > max {
+                max = temp;
+                cut = Some(pos);
+            }
+        }
+        (max, cut)
+    }
+
+    /// Splits `cube` in two along the best cut, or returns `None` if it can't
+    /// be split
+    fn cut(&self, cube: &mut ColorBox) -> Option<ColorBox> {
+        let whole = [&self.red, &self.green, &self.blue, &self.weight].map(|m| volume(cube, m));
+        let (max_r, cut_r) = self.maximize(cube, Axis::Red, whole);
+        let (max_g, cut_g) = self.maximize(cube, Axis::Green, whole);
+        let (max_b, cut_b) = self.maximize(cube, Axis::Blue, whole);
+
+        let mut other = *cube;
+        if max_r >= max_g && max_r >= max_b {
+            let cut = cut_r?;
+            cube.r1 = cut;
+            other.r0 = cut;
+        } else if max_g >= max_r && max_g >= max_b {
+            let cut = cut_g?;
+            cube.g1 = cut;
+            other.g0 = cut;
+        } else {
+            let cut = cut_b?;
+            cube.b1 = cut;
+            other.b0 = cut;
+        }
+        Some(other)
+    }
+}
+
+/// Returns a palette of at most `palette_size` colors. Boxes are split one at
+/// a time, always picking the box with the largest variance
+pub fn palette(pixels: &[RgbU8], palette_size: usize) -> Vec<RgbU8> {
+    let moments = Moments::new(pixels);
+    let mut boxes = vec![ColorBox {
+        r1: SIDE - 1,
+        g1: SIDE - 1,
+        b1: SIDE - 1,
+        ..Default::default()
+    }];
+    let mut variances = vec![0.0];
+
+    let mut next = 0;
+    while boxes.len() < palette_size {
+        let split = moments.cut(&mut boxes[next]);
+        let variance = |cube: &ColorBox| {
+            if cube.volume() > 1 {
+                moments.variance(cube)
+            } else {
+                0.0
+            }
+        };
+        match split {
+            Some(other) => {
+                variances[next] = variance(&boxes[next]);
+                variances.push(variance(&other));
+                boxes.push(other);
+            }
+            None => variances[next] = 0.0,
+        }
+
+        let (i, &max) = variances
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        if max <= 0.0 {
+            break;
+        }
+        next = i;
+    }
+
+    boxes
+        .iter()
+        .filter_map(|cube| {
+            let weight = volume(cube, &moments.weight);
+            if weight == 0 {
+                return None;
+            }
+            let mean = |m: &[i64]| ((volume(cube, m) + weight / 2) / weight) as u8;
+            Some(RgbU8([
+                mean(&moments.red),
+                mean(&moments.green),
+                mean(&moments.blue),
+            ]))
+        })
+        .collect()
+}